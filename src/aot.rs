@@ -0,0 +1,296 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use cranelift::{
+    codegen::ir::{condcodes::IntCC, types::I8, Function, UserFuncName},
+    prelude::*,
+};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+use crate::{
+    cljit::{translate_program, Settings},
+    compile::OpCode,
+    runtime::Instrumentation,
+};
+
+/// Compiles `ops` into an object file with a `main` function, which runs the program on a tape of
+/// `cells` cells and uses libc's `putchar` and `getchar` for in- and output, debug ops are skipped
+pub fn compile_object(
+    ops: &[OpCode],
+    cells: usize,
+    settings: &Settings,
+) -> anyhow::Result<Vec<u8>> {
+    // the executable has no budget to call the debug function through
+    let ops: Vec<_> = ops
+        .iter()
+        .copied()
+        .filter(|op| *op != OpCode::Debug)
+        .collect();
+    let isa_builder = match cranelift_native::builder() {
+        Ok(ok) => ok,
+        Err(e) => anyhow::bail!("host maschine is not supported: {e}"),
+    };
+    let isa = isa_builder.finish(settings.flags_with_pic(true)?)?;
+    let builder = ObjectBuilder::new(isa, "brainfuck", default_libcall_names())?;
+    let mut module = ObjectModule::new(builder);
+
+    let mut object = Object {
+        pointer_type: module.target_config().pointer_type(),
+        builder_context: FunctionBuilderContext::new(),
+        module: &mut module,
+    };
+    let print = object.print_function()?;
+    let scan = object.scan_function()?;
+    let brainfuck = object.brainfuck_function(&ops)?;
+    object.main_function(cells, brainfuck, print, scan)?;
+
+    Ok(module.finish().emit()?)
+}
+
+/// Compiles `ops` into a native executable at `output` by linking the object file with `linker`
+pub fn build(
+    ops: &[OpCode],
+    cells: usize,
+    settings: &Settings,
+    output: &Path,
+    linker: &str,
+) -> anyhow::Result<()> {
+    let object = compile_object(ops, cells, settings)?;
+    let (object_path, mut file) = object_file()?;
+    let written = file.write_all(&object);
+    drop(file);
+    if let Err(e) = written {
+        std::fs::remove_file(&object_path)?;
+        return Err(e.into());
+    }
+
+    let status = Command::new(linker)
+        .arg(&object_path)
+        .arg("-o")
+        .arg(output)
+        .status();
+    std::fs::remove_file(&object_path)?;
+
+    let status = status?;
+    if !status.success() {
+        anyhow::bail!("{linker} failed with {status}");
+    }
+    Ok(())
+}
+
+/// Creates a new object file in the temporary directory, its name is unique so no file of the user
+/// is overwritten
+fn object_file() -> anyhow::Result<(PathBuf, File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let path = std::env::temp_dir().join(format!(
+            "bfjit-{}-{}.o",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+struct Object<'a> {
+    pointer_type: types::Type,
+    builder_context: FunctionBuilderContext,
+    module: &'a mut ObjectModule,
+}
+
+impl Object<'_> {
+    /// Equivalent of `printer_function`, ignores the printer object and calls `putchar`
+    fn print_function(&mut self) -> anyhow::Result<FuncId> {
+        let mut putchar = self.module.make_signature();
+        putchar.params.push(AbiParam::new(types::I32));
+        putchar.returns.push(AbiParam::new(types::I32));
+        let putchar = self
+            .module
+            .declare_function("putchar", Linkage::Import, &putchar)?;
+
+        let mut signature = Signature::new(isa::CallConv::SystemV);
+        signature
+            .params
+            .extend([AbiParam::new(self.pointer_type), AbiParam::new(I8)]);
+
+        self.define(
+            "bf_print",
+            Linkage::Local,
+            signature,
+            |module, builder, params| {
+                let putchar = module.declare_func_in_func(putchar, builder.func);
+                let value = builder.ins().uextend(types::I32, params[1]);
+                builder.ins().call(putchar, &[value]);
+                builder.ins().return_(&[]);
+            },
+        )
+    }
+
+    /// Equivalent of `scanner_function`, ignores the scanner object and calls `getchar`, which
+    /// returns 0 on the end of the input
+    fn scan_function(&mut self) -> anyhow::Result<FuncId> {
+        let mut getchar = self.module.make_signature();
+        getchar.returns.push(AbiParam::new(types::I32));
+        let getchar = self
+            .module
+            .declare_function("getchar", Linkage::Import, &getchar)?;
+
+        let mut signature = Signature::new(isa::CallConv::SystemV);
+        signature.params.push(AbiParam::new(self.pointer_type));
+        signature.returns.push(AbiParam::new(I8));
+
+        self.define(
+            "bf_scan",
+            Linkage::Local,
+            signature,
+            |module, builder, _| {
+                let getchar = module.declare_func_in_func(getchar, builder.func);
+                let call = builder.ins().call(getchar, &[]);
+                let value = builder.inst_results(call)[0];
+                let eof = builder.ins().icmp_imm(IntCC::SignedLessThan, value, 0);
+                let value = builder.ins().ireduce(I8, value);
+                let zero = builder.ins().iconst(I8, 0);
+                let value = builder.ins().select(eof, zero, value);
+                builder.ins().return_(&[value]);
+            },
+        )
+    }
+
+    fn brainfuck_function(&mut self, ops: &[OpCode]) -> anyhow::Result<FuncId> {
+        let mut ctx = self.module.make_context();
+        translate_program(
+            &mut ctx.func,
+            &mut self.builder_context,
+            self.pointer_type,
+            ops,
+            false,
+            Instrumentation::default(),
+        );
+
+        let id = self
+            .module
+            .declare_function("brainfuck", Linkage::Local, &ctx.func.signature)?;
+        self.module.define_function(id, &mut ctx)?;
+        Ok(id)
+    }
+
+    /// Allocates the tape and runs the program
+    fn main_function(
+        &mut self,
+        cells: usize,
+        brainfuck: FuncId,
+        print: FuncId,
+        scan: FuncId,
+    ) -> anyhow::Result<FuncId> {
+        let ptr = self.pointer_type;
+        let mut calloc = self.module.make_signature();
+        calloc
+            .params
+            .extend([AbiParam::new(ptr), AbiParam::new(ptr)]);
+        calloc.returns.push(AbiParam::new(ptr));
+        let calloc = self
+            .module
+            .declare_function("calloc", Linkage::Import, &calloc)?;
+
+        let mut free = self.module.make_signature();
+        free.params.push(AbiParam::new(ptr));
+        let free = self
+            .module
+            .declare_function("free", Linkage::Import, &free)?;
+
+        let mut signature = self.module.make_signature();
+        signature.returns.push(AbiParam::new(types::I32));
+
+        self.define("main", Linkage::Export, signature, |module, builder, _| {
+            let calloc = module.declare_func_in_func(calloc, builder.func);
+            let free = module.declare_func_in_func(free, builder.func);
+            let brainfuck = module.declare_func_in_func(brainfuck, builder.func);
+            let print = module.declare_func_in_func(print, builder.func);
+            let scan = module.declare_func_in_func(scan, builder.func);
+
+            let count = builder.ins().iconst(ptr, cells as i64);
+            let size = builder.ins().iconst(ptr, 1);
+            let call = builder.ins().call(calloc, &[count, size]);
+            let tape = builder.inst_results(call)[0];
+
+            let null = builder.ins().iconst(ptr, 0);
+            let print = builder.ins().func_addr(ptr, print);
+            let scan = builder.ins().func_addr(ptr, scan);
+            builder
+                .ins()
+                .call(brainfuck, &[tape, null, print, null, scan, null]);
+            builder.ins().call(free, &[tape]);
+
+            let exit_code = builder.ins().iconst(types::I32, 0);
+            builder.ins().return_(&[exit_code]);
+        })
+    }
+
+    /// Defines a function with a single block, `body` gets the block parameters
+    fn define(
+        &mut self,
+        name: &str,
+        linkage: Linkage,
+        signature: Signature,
+        body: impl FnOnce(&mut ObjectModule, &mut FunctionBuilder<'_>, &[Value]),
+    ) -> anyhow::Result<FuncId> {
+        let id = self.module.declare_function(name, linkage, &signature)?;
+        let mut ctx = self.module.make_context();
+        ctx.func = Function::with_name_signature(UserFuncName::user(0, id.as_u32()), signature);
+
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut self.builder_context);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        builder.seal_block(block);
+        let params = builder.block_params(block).to_vec();
+        body(self.module, &mut builder, &params);
+        builder.finalize();
+
+        self.module.define_function(id, &mut ctx)?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cljit::Settings, compile};
+
+    #[test]
+    fn code_object() {
+        let ops = compile::compile(b",++++++++++.");
+        let object = super::compile_object(&ops, 30000, &Settings::default()).unwrap();
+
+        assert!(object.starts_with(b"\x7fELF"));
+    }
+
+    #[test]
+    fn build_keeps_object_files() {
+        let dir = std::env::temp_dir().join(format!("bfjit-build-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ops = compile::compile(b"+++.");
+        let settings = Settings::default();
+
+        // a file of the user next to the output is neither overwritten nor deleted
+        std::fs::write(dir.join("mine.o"), b"mine").unwrap();
+        super::build(&ops, 100, &settings, &dir.join("mine"), "cc").unwrap();
+        assert_eq!(std::fs::read(dir.join("mine.o")).unwrap(), b"mine");
+        assert!(dir.join("mine").exists());
+
+        // an output with the extension of object files
+        super::build(&ops, 100, &settings, &dir.join("prog.o"), "cc").unwrap();
+        assert!(dir.join("prog.o").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
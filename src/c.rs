@@ -0,0 +1,103 @@
+use std::fmt::Write;
+
+use crate::compile::OpCode;
+
+/// Translates `ops` into a C program with a tape of `cells` cells, which uses `putchar` and
+/// `getchar` for in- and output, debug ops print to `stderr` like [`crate::dump_tape`]
+pub fn translate(ops: &[OpCode], cells: usize) -> String {
+    let mut out = String::new();
+    writeln!(out, "#include <stdio.h>").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "static unsigned char tape[{cells}];").unwrap();
+    writeln!(out).unwrap();
+    if ops.contains(&OpCode::Debug) {
+        writeln!(out, "static void dump(unsigned char *p) {{").unwrap();
+        writeln!(out, "    size_t cell = p - tape;").unwrap();
+        writeln!(
+            out,
+            "    size_t end = cell + 9 < {cells} ? cell + 9 : {cells};"
+        )
+        .unwrap();
+        writeln!(out, "    size_t start = cell > 8 ? cell - 8 : 0;").unwrap();
+        writeln!(out, "    if (start > end) start = end;").unwrap();
+        writeln!(
+            out,
+            "    fprintf(stderr, \"# pointer %zu, cells %zu..%zu:\", cell, start, end);"
+        )
+        .unwrap();
+        writeln!(out, "    for (size_t i = start; i < end; i++)").unwrap();
+        writeln!(
+            out,
+            "        fprintf(stderr, i == cell ? \" [%d]\" : \" %d\", tape[i]);"
+        )
+        .unwrap();
+        writeln!(out, "    fprintf(stderr, \"\\n\");").unwrap();
+        writeln!(out, "}}").unwrap();
+        writeln!(out).unwrap();
+    }
+    writeln!(out, "int main(void) {{").unwrap();
+    writeln!(out, "    unsigned char *p = tape;").unwrap();
+    if ops.contains(&OpCode::Input) {
+        writeln!(out, "    int c;").unwrap();
+    }
+
+    let mut depth = 1;
+    for op in ops {
+        if let OpCode::JumpIfNotZero { .. } = op {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        match *op {
+            OpCode::Right { count } => writeln!(out, "{indent}p += {count};"),
+            OpCode::Left { count } => writeln!(out, "{indent}p -= {count};"),
+            OpCode::Inc { count, offset } => writeln!(out, "{indent}p[{offset}] += {count};"),
+            OpCode::Dec { count, offset } => writeln!(out, "{indent}p[{offset}] -= {count};"),
+            OpCode::Output => writeln!(out, "{indent}putchar(*p);"),
+            OpCode::Input => writeln!(
+                out,
+                "{indent}c = getchar();\n{indent}*p = c == EOF ? 0 : c;"
+            ),
+            OpCode::JumpIfZero { .. } => {
+                depth += 1;
+                writeln!(out, "{indent}while (*p) {{")
+            }
+            OpCode::JumpIfNotZero { .. } => writeln!(out, "{indent}}}"),
+            OpCode::SetZero => writeln!(out, "{indent}*p = 0;"),
+            OpCode::Mul { factor, offset } => writeln!(
+                out,
+                "{indent}p[{offset}] += *p * {factor};\n{indent}*p = 0;"
+            ),
+            OpCode::Debug => writeln!(out, "{indent}dump(p);"),
+        }
+        .unwrap();
+    }
+
+    writeln!(out, "    return 0;").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compile,
+        interpret::Interpreter,
+        tests::{native_output, output, HELLO_INPUT},
+    };
+
+    #[test]
+    fn code_c() {
+        let ops = compile::compile(b"+++[>++<-]>.");
+        let c = super::translate(&ops, 30000);
+
+        assert!(c.contains("static unsigned char tape[30000];"));
+        assert!(c.contains("    p[0] += 3;\n    p[1] += *p * 2;\n    *p = 0;\n"));
+        assert!(c.contains("    putchar(*p);\n"));
+        assert!(!c.contains("int c;"));
+
+        let c = super::translate(&compile::compile(HELLO_INPUT), 30000);
+        if let Some(native) = native_output("cc", &[], c.as_bytes(), "c") {
+            assert_eq!(native, output::<Interpreter>(HELLO_INPUT));
+        }
+    }
+}
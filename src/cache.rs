@@ -0,0 +1,165 @@
+use std::{
+    hash::Hasher,
+    path::{Path, PathBuf},
+};
+
+use crate::{cljit, compile, compile::OpCode, jit};
+
+/// Default cache directory, `$XDG_CACHE_HOME/bfjit` or `~/.cache/bfjit`
+pub fn default_dir() -> PathBuf {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("bfjit")
+}
+
+/// Cached artifacts of one program.
+///
+/// Failing to read an entry counts as a cache miss, failing to write one only prints a warning.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// path of the entry without extension
+    path: PathBuf,
+}
+
+impl Entry {
+    /// The entry is keyed by everything which changes the generated code: the source, the
+    /// `backend`, the cell width, the optimizer, the code generator and its ABI, the language
+    /// `extensions` and the cranelift `settings`
+    pub fn new(
+        dir: &Path,
+        code: &[u8],
+        backend: &str,
+        extensions: compile::Extensions,
+        settings: &cljit::Settings,
+    ) -> Self {
+        let mut hasher = Fnv1a::default();
+        hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+        hasher.write_u16(compile::VERSION);
+        hasher.write_u8(compile::CELL_WIDTH);
+        hasher.write_u8(compile::OPT_LEVEL);
+        hasher.write_u32(jit::CODE_VERSION);
+        hasher.write(backend.as_bytes());
+        hasher.write(format!("{extensions:?}").as_bytes());
+        hasher.write(format!("{settings:?}").as_bytes());
+        hasher.write(code);
+
+        Self {
+            path: dir.join(format!("{:016x}", hasher.finish())),
+        }
+    }
+
+    /// Returns the optimized ops
+    pub fn load_ops(&self) -> Option<Vec<OpCode>> {
+        let bytecode = std::fs::read(self.path.with_extension("bfc")).ok()?;
+        compile::load(&bytecode).ok()
+    }
+
+    pub fn store_ops(&self, ops: &[OpCode]) {
+        self.store("bfc", &compile::save(ops));
+    }
+
+    /// Returns the machine code generated by [`crate::jit::Jit`], only if its length and checksum
+    /// match the header written by [`Entry::store_code`], the code is executed as is
+    pub fn load_code(&self) -> Option<Vec<u8>> {
+        let entry = std::fs::read(self.path.with_extension("jit")).ok()?;
+        let mut reader = compile::Reader::new(&entry);
+        let len = reader.u64().ok()?;
+        let sum = reader.u64().ok()?;
+        let code = reader.rest();
+        (len == code.len() as u64 && sum == checksum(code)).then(|| code.to_vec())
+    }
+
+    /// Stores the code after a header with its length and checksum
+    pub fn store_code(&self, code: &[u8]) {
+        let mut entry = Vec::with_capacity(16 + code.len());
+        entry.extend((code.len() as u64).to_le_bytes());
+        entry.extend(checksum(code).to_le_bytes());
+        entry.extend(code);
+        self.store("jit", &entry);
+    }
+
+    fn store(&self, extension: &str, content: &[u8]) {
+        let path = self.path.with_extension(extension);
+        // write to a temporary file first, so concurrent runs never read partial entries
+        let tmp = self
+            .path
+            .with_extension(format!("{extension}.{}", std::process::id()));
+        let result = self
+            .path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&tmp, content))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            eprintln!("Could not write cache entry {}: {e}", path.display());
+            _ = std::fs::remove_file(&tmp);
+        }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv1a::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// 64 bit FNV-1a, unlike [`std::hash::DefaultHasher`] it is stable across builds
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Entry;
+    use crate::{cljit::Settings, compile};
+
+    #[test]
+    fn cache_round_trip() {
+        let dir = std::env::temp_dir().join(format!("bfjit-cache-test-{}", std::process::id()));
+        let settings = Settings::default();
+        let entry = Entry::new(&dir, b"+[-].", "jit", Default::default(), &settings);
+        let ops = compile::compile(b"+[-].");
+
+        assert!(entry.load_ops().is_none());
+        entry.store_ops(&ops);
+        entry.store_code(&[0xc3]);
+        assert_eq!(entry.load_ops().unwrap(), ops);
+        assert_eq!(entry.load_code().unwrap(), [0xc3]);
+
+        // corrupted or truncated code is a cache miss instead of being executed
+        let path = entry.path.with_extension("jit");
+        let stored = std::fs::read(&path).unwrap();
+        let mut corrupted = stored.clone();
+        *corrupted.last_mut().unwrap() = 0xcc;
+        std::fs::write(&path, &corrupted).unwrap();
+        assert!(entry.load_code().is_none());
+        std::fs::write(&path, &stored[..stored.len() - 1]).unwrap();
+        assert!(entry.load_code().is_none());
+        std::fs::write(&path, [0xc3]).unwrap();
+        assert!(entry.load_code().is_none());
+
+        let other = Entry::new(&dir, b"+[-].", "crane-lift", Default::default(), &settings);
+        assert!(other.load_ops().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
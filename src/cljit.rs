@@ -1,86 +1,181 @@
-use cranelift::{codegen::ir::types::I8, prelude::*};
+use cranelift::{
+    codegen::ir::{types::I8, SourceLoc},
+    prelude::*,
+};
 use cranelift_jit::{JITBuilder, JITModule};
 use cranelift_module::{Linkage, Module};
 
-use crate::{compile::OpCode, printer_function, scanner_function, JitFunc, Measured, Runner};
+use crate::{
+    compile::OpCode,
+    disasm, printer_function,
+    runtime::{self, Budget, Instrumentation},
+    scanner_function,
+    source::Source,
+    JitFunc, Measured, Options, PrinterFunc, RunError, Runner, ScannerFunc,
+};
+
+/// Signature of a loop compiled by [`Jit::compile_loop`], gets the index of the current cell
+/// before the loop and returns the index after it
+pub(crate) type LoopFunc = extern "C" fn(
+    *mut u8,
+    *mut crate::Printer,
+    PrinterFunc,
+    *mut crate::Scanner,
+    ScannerFunc,
+    *mut Budget,
+    usize,
+) -> usize;
+
+/// Cranelift code generator settings
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub opt_level: OptLevel,
+    /// Runs the Cranelift IR verifier during compilation
+    pub verify: bool,
+    /// Additional `(name, value)` pairs passed to the cranelift settings builder
+    pub flags: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum OptLevel {
+    #[default]
+    None,
+    Speed,
+    SpeedAndSize,
+}
+
+impl Settings {
+    /// Builds the cranelift flags, fails if one of the `flags` is unknown or has an invalid value
+    pub fn flags(&self) -> anyhow::Result<settings::Flags> {
+        self.flags_with_pic(false)
+    }
+
+    /// Builds the cranelift flags for position independent (`pic`) or absolute code
+    pub(crate) fn flags_with_pic(&self, pic: bool) -> anyhow::Result<settings::Flags> {
+        let opt_level = match self.opt_level {
+            OptLevel::None => "none",
+            OptLevel::Speed => "speed",
+            OptLevel::SpeedAndSize => "speed_and_size",
+        };
+
+        let mut flag_builder = settings::builder();
+        flag_builder.set("use_colocated_libcalls", "false")?;
+        flag_builder.set("is_pic", &pic.to_string())?;
+        flag_builder.set("opt_level", opt_level)?;
+        flag_builder.set("enable_verifier", &self.verify.to_string())?;
+        for (name, value) in &self.flags {
+            flag_builder.set(name, value)?;
+        }
+        Ok(settings::Flags::new(flag_builder))
+    }
+}
 
 pub struct ClJit {
     #[allow(dead_code)]
     jit: Jit, // has ownership of code
     code: *const u8,
+    /// ops of the program, for the profile and the trace
+    ops: Vec<OpCode>,
 }
 
 impl ClJit {
-    fn compile(ops: &mut [OpCode]) -> Self {
-        let mut jit = Jit::new().unwrap();
+    fn compile(ops: &mut [OpCode], options: &Options) -> Self {
+        let mut jit = Jit::new(&options.cranelift).unwrap();
         Self {
-            code: jit.compile(ops).unwrap(),
+            code: jit.compile(ops, Instrumentation::new(options)).unwrap(),
+            ops: ops.to_vec(),
             jit,
         }
     }
 
-    fn run(&self, cells: &mut [u8], printer: &mut crate::Printer, scanner: &mut crate::Scanner) {
+    /// Disassembles the finalized function for `ops`, annotated with the op each instruction
+    /// belongs to and its position in the `source`
+    pub fn disassemble(
+        ops: &[OpCode],
+        settings: &Settings,
+        source: Option<&Source>,
+    ) -> anyhow::Result<String> {
+        let mut jit = Jit::new(settings)?;
+        let (code, size, marks) = jit.compile_with_marks(ops, Instrumentation::default())?;
+        let code = unsafe { std::slice::from_raw_parts(code, size) };
+        Ok(disasm::disassemble(code, ops, &marks, source))
+    }
+
+    /// Returns the cranelift IR generated for `ops`
+    pub fn clif(ops: &[OpCode], settings: &Settings) -> anyhow::Result<String> {
+        let mut jit = Jit::new(settings)?;
+        Ok(jit.clif(ops))
+    }
+
+    fn run(
+        &self,
+        cells: &mut Vec<u8>,
+        printer: &mut crate::Printer,
+        scanner: &mut crate::Scanner,
+        options: &Options,
+    ) -> Result<(), RunError> {
         let func: JitFunc = unsafe { std::mem::transmute(self.code) };
         let printer = printer as *mut crate::Printer;
         let scanner = scanner as *mut crate::Scanner;
+        let mut budget = Budget::for_run(options, &self.ops, cells)?;
+        let cells = budget.tape_ptr(cells);
 
         func(
-            cells.as_mut_ptr(),
+            cells,
             printer,
             printer_function,
             scanner,
             scanner_function,
+            &mut budget as *mut Budget as *mut u8,
         );
+        budget.result(budget.cell)
     }
 }
 
 impl Runner for ClJit {
     fn exec(
         ops: &mut [OpCode],
-        cells: &mut [u8],
+        cells: &mut Vec<u8>,
         printer: &mut crate::Printer,
         scanner: &mut crate::Scanner,
-    ) {
-        ClJit::compile(ops).run(cells, printer, scanner);
+        options: &Options,
+    ) -> Result<(), RunError> {
+        ClJit::compile(ops, options).run(cells, printer, scanner, options)
     }
 
     fn exec_bench(
         ops: &mut [OpCode],
-        cells: &mut [u8],
+        cells: &mut Vec<u8>,
         printer: &mut crate::Printer,
         scanner: &mut crate::Scanner,
         count: usize,
-    ) -> Measured<()> {
+        options: &Options,
+    ) -> Result<Measured<()>, RunError> {
         let mut m = Measured::new();
 
-        let cljit = m.measure("compile cranelift", || ClJit::compile(ops));
+        let cljit = m.measure("compile cranelift", || ClJit::compile(ops, options));
 
-        for i in 0..count {
-            m.measure(format!("cranelift {i}"), || {
-                cljit.run(cells, printer, scanner);
-            });
+        for _ in 0..count {
+            m.measure_run(|| cljit.run(cells, printer, scanner, options))?;
         }
 
-        m
+        Ok(m)
     }
 }
 
-struct Jit {
+pub(crate) struct Jit {
     builder_context: FunctionBuilderContext,
     ctx: codegen::Context,
     module: JITModule,
 }
 
 impl Jit {
-    fn new() -> anyhow::Result<Self> {
-        let mut flag_builder = settings::builder();
-        flag_builder.set("use_colocated_libcalls", "false")?;
-        flag_builder.set("is_pic", "false")?;
+    pub(crate) fn new(settings: &Settings) -> anyhow::Result<Self> {
         let isa_builder = match cranelift_native::builder() {
             Ok(ok) => ok,
             Err(e) => anyhow::bail!("host maschine is not supported: {e}"),
         };
-        let isa = isa_builder.finish(settings::Flags::new(flag_builder))?;
+        let isa = isa_builder.finish(settings.flags()?)?;
         let builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
 
         let module = JITModule::new(builder);
@@ -92,8 +187,22 @@ impl Jit {
         })
     }
 
-    fn compile(&mut self, ops: &[OpCode]) -> anyhow::Result<*const u8> {
-        self.translate(ops);
+    fn compile(
+        &mut self,
+        ops: &[OpCode],
+        instrumentation: Instrumentation,
+    ) -> anyhow::Result<*const u8> {
+        Ok(self.compile_with_marks(ops, instrumentation)?.0)
+    }
+
+    /// returns the code, its size and the code offset at which each op starts
+    #[allow(clippy::type_complexity)]
+    fn compile_with_marks(
+        &mut self,
+        ops: &[OpCode],
+        instrumentation: Instrumentation,
+    ) -> anyhow::Result<(*const u8, usize, Vec<(usize, Option<usize>)>)> {
+        self.translate(ops, instrumentation);
 
         let id =
             self.module
@@ -101,61 +210,182 @@ impl Jit {
 
         self.module.define_function(id, &mut self.ctx)?;
 
-        /*
-        // Print the IR
-        let mut func_buf = String::new();
-        write_function(&mut func_buf, &self.ctx.func).unwrap();
-        println!("{func_buf}");
-        */
+        let compiled = self.ctx.compiled_code().unwrap();
+        let size = compiled.code_info().total_size as usize;
+        let mut marks = Vec::new();
+        let mut end = 0;
+        for srcloc in compiled.buffer.get_srclocs_sorted() {
+            let start = srcloc.start as usize;
+            let op = (!srcloc.loc.is_default()).then_some(srcloc.loc.bits() as usize);
+            if start > end {
+                marks.push((end, None));
+            }
+            if marks.last().map(|(_, last)| *last) != Some(op) {
+                marks.push((start, op));
+            }
+            end = srcloc.end as usize;
+        }
+        if end < size {
+            marks.push((end, None));
+        }
 
         self.module.clear_context(&mut self.ctx);
         self.module.finalize_definitions()?;
-        Ok(self.module.get_finalized_function(id))
+        Ok((self.module.get_finalized_function(id), size, marks))
     }
 
-    fn translate(&mut self, ops: &[OpCode]) {
+    /// Compiles the loop `ops` (from `[` to the matching `]`) as function `loop{id}`, the code
+    /// lives as long as `self`
+    pub(crate) fn compile_loop(
+        &mut self,
+        ops: &[OpCode],
+        id: usize,
+        instrumentation: Instrumentation,
+    ) -> anyhow::Result<LoopFunc> {
         let pointer_type = self.module.target_config().pointer_type();
-        let ptr_arg = AbiParam::new(pointer_type);
-        self.ctx.func.signature.params.extend([
-            ptr_arg, // cells
-            ptr_arg, // print object
-            ptr_arg, // print trait
-            ptr_arg, // scan object
-            ptr_arg, // scan trait
-        ]);
-
-        let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_context);
-
-        let entry_block = builder.create_block();
-        builder.append_block_params_for_function_params(entry_block);
-        builder.switch_to_block(entry_block);
-        builder.seal_block(entry_block);
-
-        let cells = builder.block_params(entry_block)[0];
-        let mut trans = OpTranslator::new(pointer_type, builder, cells, entry_block);
-        for op in ops {
-            trans.translate(*op);
-        }
+        translate_program(
+            &mut self.ctx.func,
+            &mut self.builder_context,
+            pointer_type,
+            ops,
+            true,
+            instrumentation,
+        );
 
-        trans.builder.ins().return_(&[]);
-        trans.builder.finalize();
+        let name = format!("loop{id}");
+        let id = self
+            .module
+            .declare_function(&name, Linkage::Export, &self.ctx.func.signature)?;
+        self.module.define_function(id, &mut self.ctx)?;
+        self.module.clear_context(&mut self.ctx);
+        self.module.finalize_definitions()?;
+
+        let code = self.module.get_finalized_function(id);
+        Ok(unsafe { std::mem::transmute::<*const u8, LoopFunc>(code) })
+    }
+
+    fn clif(&mut self, ops: &[OpCode]) -> String {
+        self.translate(ops, Instrumentation::default());
+        let clif = self.ctx.func.display().to_string();
+        self.module.clear_context(&mut self.ctx);
+        clif
+    }
+
+    fn translate(&mut self, ops: &[OpCode], instrumentation: Instrumentation) {
+        let pointer_type = self.module.target_config().pointer_type();
+        translate_program(
+            &mut self.ctx.func,
+            &mut self.builder_context,
+            pointer_type,
+            ops,
+            false,
+            instrumentation,
+        );
     }
 }
 
+/// Translates `ops` into the body of `func`, which gets the signature of [`JitFunc`], or of
+/// [`LoopFunc`] if `resume` is set. The `instrumentation` uses the budget argument.
+pub(crate) fn translate_program(
+    func: &mut codegen::ir::Function,
+    builder_context: &mut FunctionBuilderContext,
+    pointer_type: types::Type,
+    ops: &[OpCode],
+    resume: bool,
+    instrumentation: Instrumentation,
+) {
+    let ptr_arg = AbiParam::new(pointer_type);
+    func.signature.params.extend([
+        ptr_arg, // cells
+        ptr_arg, // print object
+        ptr_arg, // print trait
+        ptr_arg, // scan object
+        ptr_arg, // scan trait
+        ptr_arg, // budget
+    ]);
+    if resume {
+        func.signature.params.push(ptr_arg); // cell index
+        func.signature.returns.push(ptr_arg);
+    }
+
+    let mut builder = FunctionBuilder::new(func, builder_context);
+
+    let entry_block = builder.create_block();
+    builder.append_block_params_for_function_params(entry_block);
+    builder.switch_to_block(entry_block);
+    builder.seal_block(entry_block);
+
+    let cells = builder.block_params(entry_block)[0];
+    let mut trans = OpTranslator::new(pointer_type, builder, cells, entry_block);
+    if resume {
+        let index = trans.builder.block_params(entry_block)[6];
+        trans.builder.def_var(trans.cell_index, index);
+    } else if instrumentation.start {
+        let budget = trans.builder.block_params(entry_block)[5];
+        let index =
+            trans
+                .builder
+                .ins()
+                .load(pointer_type, trans.mem_flags, budget, runtime::CELL_OFFSET);
+        trans.builder.def_var(trans.cell_index, index);
+    }
+    if instrumentation.steps || instrumentation.grow {
+        trans.stop = Some(trans.builder.create_block());
+    }
+    trans.count_steps = instrumentation.steps;
+    trans.count_loops = instrumentation.loops;
+    trans.trace = instrumentation.trace;
+    trans.grow_tape = instrumentation.grow;
+    for (index, op) in ops.iter().enumerate() {
+        trans.builder.set_srcloc(SourceLoc::new(index as u32));
+        trans.translate(index, *op);
+    }
+
+    trans.builder.set_srcloc(SourceLoc::default());
+    trans.finish(resume);
+    if let Some(stop) = trans.stop {
+        // stores the current cell in the budget and returns
+        trans.builder.switch_to_block(stop);
+        trans.builder.seal_block(stop);
+        let budget = trans.builder.block_params(entry_block)[5];
+        let index = trans.builder.use_var(trans.cell_index);
+        trans
+            .builder
+            .ins()
+            .store(trans.mem_flags, index, budget, runtime::CELL_OFFSET);
+        trans.finish(resume);
+    }
+    trans.builder.finalize();
+}
+
 struct OpTranslator<'a> {
     ptr: types::Type,
     builder: FunctionBuilder<'a>,
     cell_index: Variable,
-    cells: Value,
+    cells: Variable,
     mem_flags: MemFlags,
     stack: Vec<(Block, Block)>,
     block: Block,
+    /// block which is entered once the budget is used up or the tape can not grow, only exists
+    /// for limited code and code which grows the tape
+    stop: Option<Block>,
+    /// counts the steps at every `]`
+    count_steps: bool,
+    /// counts the executions of `[` and `]`
+    count_loops: bool,
+    /// calls the trace function before every op
+    trace: bool,
+    /// grows the tape before a cell right of its end is used
+    grow_tape: bool,
 }
 
 impl<'a> OpTranslator<'a> {
-    fn new(ptr: types::Type, mut builder: FunctionBuilder<'a>, cells: Value, block: Block) -> Self {
+    fn new(ptr: types::Type, mut builder: FunctionBuilder<'a>, tape: Value, block: Block) -> Self {
         let cell_index = Variable::new(0);
         builder.declare_var(cell_index, ptr);
+        let cells = Variable::new(1);
+        builder.declare_var(cells, ptr);
+        builder.def_var(cells, tape);
         Self {
             ptr,
             builder,
@@ -164,15 +394,175 @@ impl<'a> OpTranslator<'a> {
             mem_flags: MemFlags::new(),
             stack: Vec::new(),
             block,
+            stop: None,
+            count_steps: false,
+            count_loops: false,
+            trace: false,
+            grow_tape: false,
+        }
+    }
+
+    /// returns the current cell index if `resume` is set
+    fn finish(&mut self, resume: bool) {
+        if resume {
+            let index = self.builder.use_var(self.cell_index);
+            self.builder.ins().return_(&[index]);
+        } else {
+            self.builder.ins().return_(&[]);
         }
     }
 
-    fn translate(&mut self, op: OpCode) {
+    /// counts one step at the `]` with index `op`, jumps to `stop` if the budget is used up
+    fn step(&mut self, stop: Block, op: usize) {
+        let budget = self.builder.block_params(self.block)[5];
+        let counter =
+            self.builder
+                .ins()
+                .load(types::I64, self.mem_flags, budget, runtime::COUNTER_OFFSET);
+        let counter = self.builder.ins().iadd_imm(counter, -1);
+        self.builder
+            .ins()
+            .store(self.mem_flags, counter, budget, runtime::COUNTER_OFFSET);
+
+        let check_block = self.builder.create_block();
+        let continue_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(counter, continue_block, &[], check_block, &[]);
+
+        self.builder.switch_to_block(check_block);
+        self.builder.seal_block(check_block);
+        let op = self.builder.ins().iconst(types::I64, op as i64);
+        self.builder
+            .ins()
+            .store(self.mem_flags, op, budget, runtime::OP_OFFSET);
+        let check =
+            self.builder
+                .ins()
+                .load(self.ptr, self.mem_flags, budget, runtime::CHECK_OFFSET);
+        let mut check_signature = Signature::new(isa::CallConv::SystemV);
+        check_signature.params.push(AbiParam::new(self.ptr));
+        check_signature.returns.push(AbiParam::new(I8));
+        let check_signature = self.builder.import_signature(check_signature);
+        let call = self
+            .builder
+            .ins()
+            .call_indirect(check_signature, check, &[budget]);
+        let stopped = self.builder.inst_results(call)[0];
+        self.builder
+            .ins()
+            .brif(stopped, stop, &[], continue_block, &[]);
+
+        self.builder.switch_to_block(continue_block);
+        self.builder.seal_block(continue_block);
+    }
+
+    /// counts one execution of the `[` or `]` with index `op` in the loop counts of the budget
+    fn count(&mut self, op: usize) {
+        let budget = self.builder.block_params(self.block)[5];
+        let counts = self.builder.ins().load(
+            self.ptr,
+            self.mem_flags,
+            budget,
+            runtime::LOOP_COUNTS_OFFSET,
+        );
+        let offset = (op * 8) as i32;
+        let count = self
+            .builder
+            .ins()
+            .load(types::I64, self.mem_flags, counts, offset);
+        let count = self.builder.ins().iadd_imm(count, 1);
+        self.builder
+            .ins()
+            .store(self.mem_flags, count, counts, offset);
+    }
+
+    /// calls the trace function of the budget with the tape, the current cell and `op`
+    fn trace(&mut self, op: usize) {
+        let budget = self.builder.block_params(self.block)[5];
+        let trace =
+            self.builder
+                .ins()
+                .load(self.ptr, self.mem_flags, budget, runtime::TRACE_OFFSET);
+        let mut trace_signature = Signature::new(isa::CallConv::SystemV);
+        trace_signature.params.extend([AbiParam::new(self.ptr); 4]);
+        let trace_signature = self.builder.import_signature(trace_signature);
+        let cells = self.builder.use_var(self.cells);
+        let index = self.builder.use_var(self.cell_index);
+        let op = self.builder.ins().iconst(self.ptr, op as i64);
+        self.builder
+            .ins()
+            .call_indirect(trace_signature, trace, &[budget, cells, index, op]);
+    }
+
+    /// grows the tape through the budget if the cell `offset` cells right of the current one is
+    /// not in it, jumps to `stop` if the tape can not grow at the op with index `op`
+    fn grow(&mut self, stop: Block, offset: i32, op: usize) {
+        let budget = self.builder.block_params(self.block)[5];
+        let index = self.builder.use_var(self.cell_index);
+        let index = self.builder.ins().iadd_imm(index, offset as i64);
+        let tape = self
+            .builder
+            .ins()
+            .load(self.ptr, self.mem_flags, budget, runtime::TAPE_OFFSET);
+        let outside = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, index, tape);
+
+        let grow_block = self.builder.create_block();
+        let continue_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(outside, grow_block, &[], continue_block, &[]);
+
+        self.builder.switch_to_block(grow_block);
+        self.builder.seal_block(grow_block);
+        let grow = self
+            .builder
+            .ins()
+            .load(self.ptr, self.mem_flags, budget, runtime::GROW_OFFSET);
+        let mut grow_signature = Signature::new(isa::CallConv::SystemV);
+        grow_signature.params.extend([AbiParam::new(self.ptr); 3]);
+        grow_signature.returns.push(AbiParam::new(self.ptr));
+        let grow_signature = self.builder.import_signature(grow_signature);
+        let op = self.builder.ins().iconst(self.ptr, op as i64);
+        let call = self
+            .builder
+            .ins()
+            .call_indirect(grow_signature, grow, &[budget, index, op]);
+        let cells = self.builder.inst_results(call)[0];
+        self.builder.def_var(self.cells, cells);
+        self.builder
+            .ins()
+            .brif(cells, continue_block, &[], stop, &[]);
+
+        self.builder.switch_to_block(continue_block);
+        self.builder.seal_block(continue_block);
+    }
+
+    fn translate(&mut self, index: usize, op: OpCode) {
+        if self.trace {
+            self.trace(index);
+        }
+        match op {
+            OpCode::Inc { offset, .. }
+            | OpCode::Dec { offset, .. }
+            | OpCode::Mul { offset, .. }
+                if self.grow_tape && offset > 0 =>
+            {
+                self.grow(self.stop.unwrap(), offset, index);
+            }
+            _ => {}
+        }
         match op {
             OpCode::Right { count } => {
                 let var = self.builder.use_var(self.cell_index);
                 let value = self.builder.ins().iadd_imm(var, count as i64);
                 self.builder.def_var(self.cell_index, value);
+                if self.grow_tape {
+                    self.grow(self.stop.unwrap(), 0, index);
+                }
             }
             OpCode::Left { count } => {
                 let var = self.builder.use_var(self.cell_index);
@@ -220,6 +610,9 @@ impl<'a> OpTranslator<'a> {
                 self.builder.ins().store(self.mem_flags, ret, cell_index, 0);
             }
             OpCode::JumpIfZero { .. } => {
+                if self.count_loops {
+                    self.count(index);
+                }
                 let block_if_not_zero = self.builder.create_block();
                 let block_if_zero = self.builder.create_block();
 
@@ -235,6 +628,12 @@ impl<'a> OpTranslator<'a> {
             }
             OpCode::JumpIfNotZero { .. } => {
                 let (block_if_not_zero, block_if_zero) = self.stack.pop().unwrap();
+                if self.count_steps {
+                    self.step(self.stop.unwrap(), index);
+                }
+                if self.count_loops {
+                    self.count(index);
+                }
 
                 let (_, current_cell) = self.get_current_cell();
                 self.builder
@@ -247,8 +646,9 @@ impl<'a> OpTranslator<'a> {
                 self.builder.switch_to_block(block_if_zero);
             }
             OpCode::SetZero => {
+                let cells = self.builder.use_var(self.cells);
                 let index = self.builder.use_var(self.cell_index);
-                let cell_index = self.builder.ins().iadd(self.cells, index);
+                let cell_index = self.builder.ins().iadd(cells, index);
                 let zero = self.builder.ins().iconst(I8, 0);
                 self.builder
                     .ins()
@@ -268,6 +668,23 @@ impl<'a> OpTranslator<'a> {
                     .ins()
                     .store(self.mem_flags, dest_cell, dest_index, 0);
             }
+            OpCode::Debug => {
+                let budget = self.builder.block_params(self.block)[5];
+                let debug = self.builder.ins().load(
+                    self.ptr,
+                    self.mem_flags,
+                    budget,
+                    runtime::DEBUG_OFFSET,
+                );
+                let mut debug_signature = Signature::new(isa::CallConv::SystemV);
+                debug_signature.params.extend([AbiParam::new(self.ptr); 3]);
+                let debug_signature = self.builder.import_signature(debug_signature);
+                let cells = self.builder.use_var(self.cells);
+                let index = self.builder.use_var(self.cell_index);
+                self.builder
+                    .ins()
+                    .call_indirect(debug_signature, debug, &[budget, cells, index]);
+            }
         }
     }
 
@@ -322,9 +739,10 @@ fn print_function(
 fn get_current_cell(
     builder: &mut FunctionBuilder<'_>,
     cell_index: Variable,
-    cells: Value,
+    cells: Variable,
     mem_flags: MemFlags,
 ) -> (Value, Value) {
+    let cells = builder.use_var(cells);
     let index = builder.use_var(cell_index);
     let cell_index = builder.ins().iadd(cells, index);
     let current_cell = builder.ins().load(I8, mem_flags, cell_index, 0);
@@ -335,13 +753,69 @@ fn get_current_cell(
 fn get_current_cell_with_offset(
     builder: &mut FunctionBuilder<'_>,
     cell_index: Variable,
-    cells: Value,
+    cells: Variable,
     mem_flags: MemFlags,
     offset: i32,
 ) -> (Value, Value) {
+    let cells = builder.use_var(cells);
     let index = builder.use_var(cell_index);
     let cell_index = builder.ins().iadd(cells, index);
     let cell_index = builder.ins().iadd_imm(cell_index, offset as i64);
     let current_cell = builder.ins().load(I8, mem_flags, cell_index, 0);
     (cell_index, current_cell)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ClJit, OptLevel, Settings};
+    use crate::compile;
+
+    #[test]
+    fn clif_settings() {
+        let ops = compile::compile(b"+[-].");
+        let settings = Settings {
+            opt_level: OptLevel::Speed,
+            verify: true,
+            flags: vec![("regalloc_checker".to_string(), "true".to_string())],
+        };
+        assert!(settings.flags().is_ok());
+        let clif = ClJit::clif(&ops, &settings).unwrap();
+        assert!(clif.starts_with("function u0:0(i64, i64, i64, i64, i64, i64) system_v"));
+        // `+`, the `SetZero` of `[-]` and the output call of `.`
+        assert!(without_values(&clif).contains("iadd_imm v_, 1"));
+        assert!(clif.contains("iconst.i8 0"));
+        assert!(clif.contains("call_indirect sig0"));
+
+        let invalid = Settings {
+            flags: vec![("opt_level".to_string(), "fastest".to_string())],
+            ..Settings::default()
+        };
+        let Err(error) = invalid.flags() else {
+            panic!("`opt_level=fastest` has to be rejected");
+        };
+        let error = error.to_string();
+        assert!(error.contains("expected any among none, speed"), "{error}");
+        let unknown = Settings {
+            flags: vec![("no_such_flag".to_string(), "true".to_string())],
+            ..Settings::default()
+        };
+        assert!(ClJit::clif(&ops, &unknown).is_err());
+    }
+
+    /// `clif` with the number of every value replaced by `_`, which changes with unrelated codegen
+    fn without_values(clif: &str) -> String {
+        let mut out = String::with_capacity(clif.len());
+        let mut chars = clif.chars().peekable();
+        let mut previous = ' ';
+        while let Some(char) = chars.next() {
+            out.push(char);
+            let word_start = !previous.is_alphanumeric() && previous != '_';
+            if char == 'v' && word_start && chars.peek().is_some_and(char::is_ascii_digit) {
+                out.push('_');
+                while chars.next_if(char::is_ascii_digit).is_some() {}
+            }
+            previous = char;
+        }
+        out
+    }
+}
@@ -1,6 +1,10 @@
-use crate::Measured;
+use std::ops::Range;
 
-#[derive(Debug, Clone, Copy)]
+use anyhow::Context;
+
+use crate::{source::SourceMap, Measured};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpCode {
     /// Moves the cursor `count` to the right
     Right { count: u32 },
@@ -22,77 +26,321 @@ pub enum OpCode {
     SetZero,
     /// Multiplies the current cell by `factor` and writes the value to the `offset` of the current cell, after that sets the current cell to 0
     Mul { factor: u8, offset: i32 },
+    /// Prints the cell pointer and the cells around it to stderr, see [`crate::dump_tape`]
+    Debug,
+}
+
+/// Optional extensions of the language, all of them are off by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extensions {
+    /// `#` compiles to [`OpCode::Debug`]
+    pub debug_ops: bool,
+    /// The code after the first `!` is the input of the program, see [`split_input`]
+    pub bundled_input: bool,
+}
+
+/// Splits `code` at the first `!` into the program and the input bundled with it, only if
+/// [`Extensions::bundled_input`] is set
+pub fn split_input(code: &[u8], extensions: Extensions) -> (&[u8], Option<&[u8]>) {
+    match code.iter().position(|byte| *byte == b'!') {
+        Some(index) if extensions.bundled_input => (&code[..index], Some(&code[index + 1..])),
+        _ => (code, None),
+    }
 }
 
-fn compile_impl(code: &[u8]) -> Vec<OpCode> {
+/// Returns the ops of `code` and the source range of each op, the input after a `!` is skipped
+fn compile_impl(code: &[u8], extensions: Extensions) -> (Vec<OpCode>, Vec<Range<usize>>) {
+    let (code, _) = split_input(code, extensions);
     let mut ret = Vec::with_capacity(code.len());
-    let mut index = 0usize;
+    let mut spans = Vec::with_capacity(code.len());
 
-    while index < code.len() {
-        match code[index] {
-            b'+' => {
-                ret.push(OpCode::Inc {
-                    count: 1,
-                    offset: 0,
-                });
-                index += 1;
+    for (index, byte) in code.iter().enumerate() {
+        let op = match byte {
+            b'+' => OpCode::Inc {
+                count: 1,
+                offset: 0,
+            },
+            b'-' => OpCode::Dec {
+                count: 1,
+                offset: 0,
+            },
+            b'<' => OpCode::Left { count: 1 },
+            b'>' => OpCode::Right { count: 1 },
+            b'[' => OpCode::JumpIfZero { target: 0 },
+            b']' => OpCode::JumpIfNotZero { target: 0 },
+            b'.' => OpCode::Output,
+            b',' => OpCode::Input,
+            b'#' if extensions.debug_ops => OpCode::Debug,
+            _ => continue,
+        };
+        ret.push(op);
+        spans.push(index..index + 1);
+    }
+    (ret, spans)
+}
+
+/// Compiles `code` without [`Extensions`]
+pub fn compile(code: &[u8]) -> Vec<OpCode> {
+    compile_with_source_map(code, Extensions::default()).0
+}
+
+/// Compiles `code` and keeps the source range of every op
+pub fn compile_with_source_map(code: &[u8], extensions: Extensions) -> (Vec<OpCode>, SourceMap) {
+    let (mut ops, mut spans) = compile_impl(code, extensions);
+    optimize(&mut ops, &mut spans);
+    (ops, SourceMap { spans })
+}
+
+/// Compiles `code` without optimizations, every op belongs to exactly one byte of the source
+pub fn compile_unoptimized(code: &[u8], extensions: Extensions) -> (Vec<OpCode>, SourceMap) {
+    let (ops, spans) = compile_impl(code, extensions);
+    (ops, SourceMap { spans })
+}
+
+pub fn compile_meassured(code: &[u8], extensions: Extensions) -> Measured<Vec<OpCode>> {
+    let mut m = Measured::new();
+    let (mut ops, mut spans) = m.measure("compiling", || compile_impl(code, extensions));
+    m.measure("optimizing", || optimize(&mut ops, &mut spans));
+    m.set(ops);
+    m
+}
+
+/// Magic bytes at the start of every bytecode file
+pub const MAGIC: &[u8; 4] = b"BFC\0";
+/// Version of the bytecode format, increased on every incompatible change
+pub const VERSION: u16 = 2;
+/// Width of a cell in bits
+pub const CELL_WIDTH: u8 = 8;
+/// Optimization level of the ops returned by [`compile`]
+pub const OPT_LEVEL: u8 = 1;
+
+/// Returns if `code` is bytecode written by [`save`] instead of brainfuck source
+pub fn is_bytecode(code: &[u8]) -> bool {
+    code.starts_with(MAGIC)
+}
+
+/// Input bundled after the `!` of brainfuck source, see [`split_input`], bytecode has none
+pub fn bundled_input(code: &[u8], extensions: Extensions) -> Option<&[u8]> {
+    if is_bytecode(code) {
+        None
+    } else {
+        split_input(code, extensions).1
+    }
+}
+
+/// Loads `code` if it is bytecode, else compiles it
+pub fn compile_or_load(code: &[u8], extensions: Extensions) -> anyhow::Result<Vec<OpCode>> {
+    if is_bytecode(code) {
+        load(code)
+    } else {
+        Ok(compile_with_source_map(code, extensions).0)
+    }
+}
+
+/// Serializes the ops returned by [`compile`].
+///
+/// Header: magic, version (u16), cell width, optimization level, op count (u32), followed by one
+/// tag byte per op and its little endian operands. The operand of `[` and `]` is the index of the
+/// matching bracket, unmatched brackets get `u32::MAX`.
+pub fn save(ops: &[OpCode]) -> Vec<u8> {
+    let mut partners = vec![u32::MAX; ops.len()];
+    let mut open = Vec::new();
+    for (index, op) in ops.iter().enumerate() {
+        match op {
+            OpCode::JumpIfZero { .. } => open.push(index),
+            OpCode::JumpIfNotZero { .. } => {
+                if let Some(top) = open.pop() {
+                    partners[top] = index as u32;
+                    partners[index] = top as u32;
+                }
             }
-            b'-' => {
-                ret.push(OpCode::Dec {
-                    count: 1,
-                    offset: 0,
-                });
-                index += 1;
+            _ => {}
+        }
+    }
+
+    let mut out = Vec::with_capacity(12 + ops.len() * 6);
+    out.extend(MAGIC);
+    out.extend(VERSION.to_le_bytes());
+    out.extend([CELL_WIDTH, OPT_LEVEL]);
+    out.extend((ops.len() as u32).to_le_bytes());
+
+    for (op, partner) in ops.iter().zip(partners) {
+        match *op {
+            OpCode::Right { count } => {
+                out.push(0);
+                out.extend(count.to_le_bytes());
             }
-            b'<' => {
-                ret.push(OpCode::Left { count: 1 });
-                index += 1;
+            OpCode::Left { count } => {
+                out.push(1);
+                out.extend(count.to_le_bytes());
             }
-            b'>' => {
-                ret.push(OpCode::Right { count: 1 });
-                index += 1;
+            OpCode::Inc { count, offset } => {
+                out.extend([2, count]);
+                out.extend(offset.to_le_bytes());
             }
-            b'[' => {
-                ret.push(OpCode::JumpIfZero { target: 0 });
-                index += 1;
+            OpCode::Dec { count, offset } => {
+                out.extend([3, count]);
+                out.extend(offset.to_le_bytes());
             }
-            b']' => {
-                ret.push(OpCode::JumpIfNotZero { target: 0 });
-                index += 1;
+            OpCode::Output => out.push(4),
+            OpCode::Input => out.push(5),
+            OpCode::JumpIfZero { .. } => {
+                out.push(6);
+                out.extend(partner.to_le_bytes());
             }
-            b'.' => {
-                ret.push(OpCode::Output);
-                index += 1;
+            OpCode::JumpIfNotZero { .. } => {
+                out.push(7);
+                out.extend(partner.to_le_bytes());
             }
-            b',' => {
-                ret.push(OpCode::Input);
-                index += 1;
+            OpCode::SetZero => out.push(8),
+            OpCode::Mul { factor, offset } => {
+                out.extend([9, factor]);
+                out.extend(offset.to_le_bytes());
             }
-            _ => index += 1,
+            OpCode::Debug => out.push(10),
         }
     }
-    ret
+    out
 }
 
-pub fn compile(code: &[u8]) -> Vec<OpCode> {
-    let mut ops = compile_impl(code);
-    optimize(&mut ops);
-    ops
+/// Deserializes ops written by [`save`], fails on unmatched brackets. The jump targets are reset
+/// like the ones of [`compile`], the runners back patch them.
+pub fn load(code: &[u8]) -> anyhow::Result<Vec<OpCode>> {
+    let mut reader = Reader::new(code);
+
+    if reader.bytes::<4>()? != *MAGIC {
+        anyhow::bail!("not a bytecode file");
+    }
+    let version = u16::from_le_bytes(reader.bytes()?);
+    if version != VERSION {
+        anyhow::bail!("unsupported bytecode version {version}, expected {VERSION}");
+    }
+    let [cell_width, opt_level] = reader.bytes()?;
+    if cell_width != CELL_WIDTH {
+        anyhow::bail!("unsupported cell width {cell_width}, expected {CELL_WIDTH}");
+    }
+    if opt_level > OPT_LEVEL {
+        anyhow::bail!("unsupported optimization level {opt_level}");
+    }
+
+    let count = reader.u32()? as usize;
+    // every op takes at least one byte, a forged count can not allocate more than the file
+    let mut ops = Vec::with_capacity(count.min(reader.rest().len()));
+    let mut open = Vec::new();
+    for index in 0..count {
+        let op = match reader.u8()? {
+            0 => OpCode::Right {
+                count: reader.u32()?,
+            },
+            1 => OpCode::Left {
+                count: reader.u32()?,
+            },
+            2 => OpCode::Inc {
+                count: reader.u8()?,
+                offset: reader.i32()?,
+            },
+            3 => OpCode::Dec {
+                count: reader.u8()?,
+                offset: reader.i32()?,
+            },
+            4 => OpCode::Output,
+            5 => OpCode::Input,
+            6 => {
+                open.push((index, reader.u32()? as usize));
+                OpCode::JumpIfZero { target: 0 }
+            }
+            7 => {
+                let partner = reader.u32()? as usize;
+                let Some((top, top_partner)) = open.pop() else {
+                    anyhow::bail!("unmatched `]` at op {index}");
+                };
+                if partner != top || top_partner != index {
+                    anyhow::bail!(
+                        "jump targets of the brackets at op {top} and {index} do not match"
+                    );
+                }
+                OpCode::JumpIfNotZero { target: 0 }
+            }
+            8 => OpCode::SetZero,
+            9 => OpCode::Mul {
+                factor: reader.u8()?,
+                offset: reader.i32()?,
+            },
+            10 => OpCode::Debug,
+            tag => anyhow::bail!("unknown op {tag} at byte {}", reader.position - 1),
+        };
+        ops.push(op);
+    }
+    if let Some((index, _)) = open.pop() {
+        anyhow::bail!("unmatched `[` at op {index}");
+    }
+    Ok(ops)
 }
 
-pub fn compile_meassured(code: &[u8]) -> Measured<Vec<OpCode>> {
-    let mut m = Measured::new();
-    let mut ops = m.measure("compiling", || compile_impl(code));
-    m.measure("optimizing", || optimize(&mut ops));
-    m.set(ops);
-    m
+/// Reads little endian values, fails at the end of `code`
+pub(crate) struct Reader<'a> {
+    code: &'a [u8],
+    position: usize,
 }
 
-fn optimize(ops: &mut Vec<OpCode>) {
+impl<'a> Reader<'a> {
+    pub(crate) fn new(code: &'a [u8]) -> Self {
+        Self { code, position: 0 }
+    }
+
+    /// Returns the unread part of `code`
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        &self.code[self.position..]
+    }
+
+    pub(crate) fn slice(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let slice = self
+            .code
+            .get(self.position..self.position.saturating_add(len))
+            .context("unexpected end of bytecode")?;
+        self.position += len;
+        Ok(slice)
+    }
+
+    pub(crate) fn bytes<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.slice(N)?.try_into().unwrap())
+    }
+
+    pub(crate) fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    pub(crate) fn i32(&mut self) -> anyhow::Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+}
+
+/// Merges ops into more efficient ones, `spans` are the source ranges of the ops and are merged
+/// alongside them
+fn optimize(ops: &mut Vec<OpCode>, spans: &mut Vec<Range<usize>>) {
     use OpCode as Op;
     let mut read = 0usize;
     let mut write = 0usize;
 
+    // replaces the `count` ops at `read` with `op`
+    macro_rules! emit {
+        ($op:expr, $count:expr) => {{
+            let count = $count;
+            ops[write] = $op;
+            spans[write] = spans[read].start..spans[read + count - 1].end;
+            write += 1;
+            read += count;
+        }};
+    }
+
     macro_rules! count {
         ($op:pat) => {{
             ops[read..]
@@ -106,47 +354,50 @@ fn optimize(ops: &mut Vec<OpCode>) {
         match &ops[read] {
             Op::Inc { .. } => {
                 let count = count!(Op::Inc { .. });
-                ops[write] = Op::Inc {
-                    count: count as u8,
-                    offset: 0,
-                };
-                write += 1;
-                read += count;
+                emit!(
+                    Op::Inc {
+                        count: count as u8,
+                        offset: 0,
+                    },
+                    count
+                );
             }
             Op::Dec { .. } => {
                 let count = count!(Op::Dec { .. });
-                ops[write] = Op::Dec {
-                    count: count as u8,
-                    offset: 0,
-                };
-                write += 1;
-                read += count;
+                emit!(
+                    Op::Dec {
+                        count: count as u8,
+                        offset: 0,
+                    },
+                    count
+                );
             }
             Op::Left { .. } => {
                 let count = count!(Op::Left { .. });
-                ops[write] = Op::Left {
-                    count: count as u32,
-                };
-                write += 1;
-                read += count;
+                emit!(
+                    Op::Left {
+                        count: count as u32,
+                    },
+                    count
+                );
             }
             Op::Right { .. } => {
                 let count = count!(Op::Right { .. });
-                ops[write] = Op::Right {
-                    count: count as u32,
-                };
-                write += 1;
-                read += count;
+                emit!(
+                    Op::Right {
+                        count: count as u32,
+                    },
+                    count
+                );
             }
             _ => {
-                ops[write] = ops[read];
-                write += 1;
-                read += 1;
+                emit!(ops[read], 1);
             }
         }
     }
 
     ops.truncate(write);
+    spans.truncate(write);
     read = 0;
     write = 0;
 
@@ -156,56 +407,55 @@ fn optimize(ops: &mut Vec<OpCode>) {
             // [-] or [+]
             [Op::JumpIfZero { .. }, Op::Dec { .. } | Op::Inc { .. }, Op::JumpIfNotZero { .. }, ..] =>
             {
-                ops[write] = Op::SetZero;
-                write += 1;
-                read += 3;
+                emit!(Op::SetZero, 3);
             }
             // Add/Sub with offset
             // >>>+<<<
             [Op::Right { count: r_count }, change @ Op::Dec { .. } | change @ Op::Inc { .. }, Op::Left { count: l_count }, ..]
                 if *r_count == *l_count =>
             {
-                ops[write] = match *change {
-                    Op::Dec { count, .. } => Op::Dec {
-                        count,
-                        offset: *r_count as i32,
+                emit!(
+                    match *change {
+                        Op::Dec { count, .. } => Op::Dec {
+                            count,
+                            offset: *r_count as i32,
+                        },
+                        Op::Inc { count, .. } => Op::Inc {
+                            count,
+                            offset: *r_count as i32,
+                        },
+                        _ => unreachable!(),
                     },
-                    Op::Inc { count, .. } => Op::Inc {
-                        count,
-                        offset: *r_count as i32,
-                    },
-                    _ => unreachable!(),
-                };
-                write += 1;
-                read += 3;
+                    3
+                );
             }
             // Add/Sub with offset
             // <<<+>>>
             [Op::Left { count: l_count }, change @ Op::Dec { .. } | change @ Op::Inc { .. }, Op::Right { count: r_count }, ..]
                 if *r_count == *l_count =>
             {
-                ops[write] = match *change {
-                    Op::Dec { count, .. } => Op::Dec {
-                        count,
-                        offset: -(*r_count as i32),
-                    },
-                    Op::Inc { count, .. } => Op::Inc {
-                        count,
-                        offset: -(*r_count as i32),
+                emit!(
+                    match *change {
+                        Op::Dec { count, .. } => Op::Dec {
+                            count,
+                            offset: -(*r_count as i32),
+                        },
+                        Op::Inc { count, .. } => Op::Inc {
+                            count,
+                            offset: -(*r_count as i32),
+                        },
+                        _ => unreachable!(),
                     },
-                    _ => unreachable!(),
-                };
-                write += 1;
-                read += 3;
+                    3
+                );
             }
             _ => {
-                ops[write] = ops[read];
-                write += 1;
-                read += 1;
+                emit!(ops[read], 1);
             }
         }
     }
     ops.truncate(write);
+    spans.truncate(write);
 
     read = 0;
     write = 0;
@@ -217,21 +467,114 @@ fn optimize(ops: &mut Vec<OpCode>) {
                 count: 1,
                 offset: 0,
             }, Op::JumpIfNotZero { .. }, ..] => {
-                ops[write] = Op::Mul {
-                    factor: *count,
-                    offset: *offset,
-                };
-                read += 4;
-                write += 1;
+                emit!(
+                    Op::Mul {
+                        factor: *count,
+                        offset: *offset,
+                    },
+                    4
+                );
             }
             _ => {
-                ops[write] = ops[read];
-                write += 1;
-                read += 1;
+                emit!(ops[read], 1);
             }
         }
     }
 
     ops.truncate(write);
     ops.shrink_to(write);
+    spans.truncate(write);
+    spans.shrink_to(write);
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn bytecode_round_trip() {
+        let ops = super::compile(b"+++[>++<-]>>,[.,]<<[-]-<<+>>");
+        let bytecode = super::save(&ops);
+
+        assert!(super::is_bytecode(&bytecode));
+        assert_eq!(super::load(&bytecode).unwrap(), ops);
+        assert!(super::load(&bytecode[..bytecode.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn bytecode_malformed() {
+        use super::OpCode;
+
+        let error = |bytecode: &[u8]| super::load(bytecode).unwrap_err().to_string();
+        let header = &super::save(&[])[..8];
+
+        // a forged op count does not allocate memory for the ops
+        let mut huge = header.to_vec();
+        huge.extend(u32::MAX.to_le_bytes());
+        assert_eq!(error(&huge), "unexpected end of bytecode");
+
+        let lone = super::save(&[OpCode::JumpIfNotZero { target: 0 }]);
+        assert_eq!(error(&lone), "unmatched `]` at op 0");
+        let open = super::save(&[OpCode::JumpIfZero { target: 0 }]);
+        assert_eq!(error(&open), "unmatched `[` at op 0");
+
+        // `[]` with the operand of `[` pointing out of the program
+        let mut bytecode = super::save(&super::compile(b"[]"));
+        bytecode[13..17].copy_from_slice(&7u32.to_le_bytes());
+        assert_eq!(
+            error(&bytecode),
+            "jump targets of the brackets at op 0 and 1 do not match"
+        );
+    }
+
+    #[test]
+    fn debug_ops() {
+        use super::{Extensions, OpCode};
+
+        let extensions = Extensions {
+            debug_ops: true,
+            bundled_input: true,
+        };
+        let (ops, _) = super::compile_with_source_map(b"+#+!#", extensions);
+        assert_eq!(
+            ops,
+            [
+                OpCode::Inc {
+                    count: 1,
+                    offset: 0
+                },
+                OpCode::Debug,
+                OpCode::Inc {
+                    count: 1,
+                    offset: 0
+                },
+            ]
+        );
+        assert_eq!(super::load(&super::save(&ops)).unwrap(), ops);
+        assert_eq!(super::compile(b"+#+!#").len(), 1);
+        assert_eq!(
+            super::split_input(b"+#+!#", extensions),
+            (&b"+#+"[..], Some(&b"#"[..]))
+        );
+
+        // both extensions work on their own
+        let debug_ops = Extensions {
+            debug_ops: true,
+            ..Extensions::default()
+        };
+        assert_eq!(
+            super::split_input(b"+#+!#", debug_ops),
+            (&b"+#+!#"[..], None)
+        );
+        let bundled_input = Extensions {
+            bundled_input: true,
+            ..Extensions::default()
+        };
+        let (ops, _) = super::compile_with_source_map(b"+#+!#", bundled_input);
+        assert_eq!(
+            ops,
+            [OpCode::Inc {
+                count: 2,
+                offset: 0
+            }]
+        );
+    }
 }
@@ -0,0 +1,277 @@
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use crate::compile::OpCode;
+
+/// Records which cells the runs read and write and how far the pointer moves, clones share the
+/// records.
+///
+/// Indices are relative to the start cell, negative ones are left of it.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage(Arc<Mutex<Usage>>);
+
+/// Tape usage of one or more runs
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Usage {
+    /// lowest and highest index of a read or written cell
+    touched: Option<(isize, isize)>,
+    /// written cells in the tape
+    written: Vec<bool>,
+    /// written cells out of the tape
+    written_outside: HashSet<isize>,
+    /// farthest distance of the pointer from the start cell
+    excursion: usize,
+    /// length of the tape
+    tape: usize,
+    /// index of the start cell in the tape
+    start: usize,
+}
+
+/// Summary of the tape usage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Lowest index of a read or written cell
+    pub lowest: isize,
+    /// Highest index of a read or written cell
+    pub highest: isize,
+    /// Number of distinct written cells
+    pub written: usize,
+    /// Farthest distance of the pointer from the start cell in either direction, the offsets of
+    /// merged ops count as moves of the pointer
+    pub excursion: usize,
+    /// Length of the tape of the runs
+    pub tape: usize,
+    /// Index of the start cell in the tape
+    pub start: usize,
+}
+
+impl Usage {
+    pub(crate) fn new(tape: usize, start: usize) -> Self {
+        Self {
+            written: vec![false; tape],
+            tape,
+            start,
+            ..Self::default()
+        }
+    }
+
+    /// Records the op `op` which is executed with the pointer at `cell`
+    #[inline]
+    pub(crate) fn record(&mut self, op: OpCode, cell: usize) {
+        // pointers left of the tape wrap around
+        let pointer = cell.wrapping_sub(self.start) as isize;
+        match op {
+            OpCode::Right { count } => self.point(pointer + count as isize),
+            OpCode::Left { count } => self.point(pointer - count as isize),
+            OpCode::Inc { offset, .. } | OpCode::Dec { offset, .. } => {
+                self.write(pointer + offset as isize)
+            }
+            OpCode::Input | OpCode::SetZero => self.write(pointer),
+            OpCode::Output | OpCode::JumpIfZero { .. } | OpCode::JumpIfNotZero { .. } => {
+                self.touch(pointer)
+            }
+            OpCode::Mul { offset, .. } => {
+                self.write(pointer + offset as isize);
+                self.write(pointer);
+            }
+            OpCode::Debug => {}
+        }
+    }
+
+    fn point(&mut self, pointer: isize) {
+        self.excursion = self.excursion.max(pointer.unsigned_abs());
+    }
+
+    fn touch(&mut self, cell: isize) {
+        self.point(cell);
+        self.touched = Some(match self.touched {
+            Some((lowest, highest)) => (lowest.min(cell), highest.max(cell)),
+            None => (cell, cell),
+        });
+    }
+
+    fn write(&mut self, cell: isize) {
+        self.touch(cell);
+        match tape_index(cell, self.start, self.tape) {
+            Some(cell) => self.written[cell] = true,
+            None => _ = self.written_outside.insert(cell),
+        }
+    }
+
+    /// Follows the tape of a run which grew to `tape` cells
+    pub(crate) fn resize(&mut self, tape: usize) {
+        if tape <= self.tape {
+            return;
+        }
+        self.tape = tape;
+        self.written.resize(tape, false);
+        let (start, written) = (self.start, &mut self.written);
+        self.written_outside
+            .retain(|cell| match tape_index(*cell, start, tape) {
+                Some(index) => {
+                    written[index] = true;
+                    false
+                }
+                None => true,
+            });
+    }
+
+    fn add(&mut self, other: &Usage) {
+        if let Some((lowest, highest)) = other.touched {
+            self.touch(lowest);
+            self.touch(highest);
+        }
+        if self.written.len() < other.written.len() {
+            self.written.resize(other.written.len(), false);
+        }
+        for (written, other) in self.written.iter_mut().zip(&other.written) {
+            *written |= other;
+        }
+        self.written_outside.extend(&other.written_outside);
+        self.excursion = self.excursion.max(other.excursion);
+        self.tape = self.tape.max(other.tape);
+        self.start = self.start.max(other.start);
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        let (lowest, highest) = self.touched?;
+        Some(Stats {
+            lowest,
+            highest,
+            written: self.written.iter().filter(|written| **written).count()
+                + self.written_outside.len(),
+            excursion: self.excursion,
+            tape: self.tape,
+            start: self.start,
+        })
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the usage of one run
+    pub(crate) fn add(&self, usage: &Usage) {
+        self.0.lock().unwrap().add(usage);
+    }
+
+    /// Usage of all runs, none if no cell was read or written
+    pub fn stats(&self) -> Option<Stats> {
+        self.0.lock().unwrap().stats()
+    }
+}
+
+/// Index in the tape of the cell `cell` cells right of the start, if it is in the tape
+fn tape_index(cell: isize, start: usize, tape: usize) -> Option<usize> {
+    let index = cell.checked_add_unsigned(start)?;
+    usize::try_from(index).ok().filter(|index| *index < tape)
+}
+
+/// Description of the `stats`, with warnings about cells out of the tape
+pub fn report(stats: Option<Stats>) -> String {
+    let Some(stats) = stats else {
+        return "no cell was touched\n".to_string();
+    };
+    let mut out = format!(
+        "cells touched: {}..={}, written: {}, pointer excursion: {}\n",
+        stats.lowest, stats.highest, stats.written, stats.excursion
+    );
+    let left = stats.lowest.min(0).unsigned_abs();
+    let right = (stats.highest + 1).max(0) as usize;
+    if left > stats.start {
+        if stats.start == 0 {
+            writeln!(out, "warning: the program uses cells left of the first one").unwrap();
+        } else {
+            writeln!(
+                out,
+                "warning: the program uses {left} cells left of the start, the tape has {}",
+                stats.start
+            )
+            .unwrap();
+        }
+    } else if right > stats.tape - stats.start {
+        writeln!(
+            out,
+            "warning: the program uses {right} cells from the start on, the tape has {}",
+            stats.tape - stats.start
+        )
+        .unwrap();
+    } else if stats.start == 0 {
+        writeln!(out, "--cells {right} would suffice").unwrap();
+    } else {
+        // the start is in the middle of the tape
+        writeln!(out, "--cells {} would suffice", 2 * left.max(right)).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{report, Coverage, Stats, Usage};
+    use crate::{
+        cljit::ClJit, compile, compile::OpCode, interpret::Interpreter, jit::Jit,
+        threaded::Threaded, tiered::Tiered, Options, Printer, Runner, Scanner,
+    };
+
+    fn stats<T: Runner>(code: &[u8]) -> Option<Stats> {
+        let mut ops = compile::compile(code);
+        let coverage = Coverage::new();
+        let options = Options {
+            coverage: Some(coverage.clone()),
+            ..Options::default()
+        };
+        let mut cells = vec![0u8; 100];
+        T::exec(
+            &mut ops,
+            &mut cells,
+            &mut Printer::new(|_| {}),
+            &mut Scanner::new(|| 0),
+            &options,
+        )
+        .unwrap();
+        coverage.stats()
+    }
+
+    #[test]
+    fn coverage_runners() {
+        // writes the cells 0 to 2, the output reads cell 4
+        let code = b"+>>++[<+>-]>>.<<<<";
+        let expected = Some(Stats {
+            lowest: 0,
+            highest: 4,
+            written: 3,
+            excursion: 4,
+            tape: 100,
+            start: 0,
+        });
+        assert_eq!(stats::<Interpreter>(code), expected);
+        assert_eq!(stats::<Threaded>(code), expected);
+        assert_eq!(stats::<Tiered>(code), expected);
+        assert_eq!(stats::<Jit>(code), expected);
+        assert_eq!(stats::<ClJit>(code), expected);
+        assert!(report(expected).contains("--cells 5 would suffice"));
+
+        // the runners can not move left of the tape, the pointer of `<+` wraps around
+        let mut usage = Usage::new(100, 0);
+        usage.record(OpCode::Left { count: 1 }, 0);
+        usage.record(
+            OpCode::Inc {
+                count: 1,
+                offset: 0,
+            },
+            usize::MAX,
+        );
+        let stats = usage.stats().unwrap();
+        assert_eq!((stats.lowest, stats.written, stats.excursion), (-1, 1, 1));
+        assert_eq!(
+            report(Some(stats)),
+            "cells touched: -1..=-1, written: 1, pointer excursion: 1\n\
+             warning: the program uses cells left of the first one\n"
+        );
+    }
+}
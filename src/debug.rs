@@ -0,0 +1,355 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    compile::{self, Extensions, OpCode},
+    machine::{Machine, Status},
+    source::{Lines, SourceMap},
+    Printer,
+};
+
+const HELP: &str = "\
+s [n]          step n source characters
+o [n]          step n optimized ops
+n              step over the loop starting at the current `[`
+c              continue until a breakpoint, a watchpoint or the end
+b line[:col]   set a breakpoint
+d line[:col]   delete a breakpoint
+w cell         stop when the cell changes
+u cell         remove a watchpoint
+t [radius]     show the tape around the pointer
+l              show the current position
+i text         feed a line of input to the program
+eof            close the input of the program
+q              quit";
+
+/// Source level debugger on top of a [`Machine`].
+///
+/// The machine runs the unoptimized ops, so every op belongs to one character of the source, the
+/// ops of [`compile::compile`] are used to step by optimized op.
+pub struct Debugger {
+    machine: Machine,
+    code: Vec<u8>,
+    lines: Lines,
+    /// spans of the unoptimized ops run by the machine
+    source_map: SourceMap,
+    /// index of the optimized op each unoptimized op belongs to
+    optimized: Vec<usize>,
+    /// indices of unoptimized ops
+    breakpoints: BTreeSet<usize>,
+    /// watched cells and their last value
+    watchpoints: BTreeMap<usize, u8>,
+}
+
+/// Reason for the machine to stop
+enum Stop {
+    Breakpoint,
+    Watchpoint { cell: usize, old: u8, new: u8 },
+}
+
+impl Debugger {
+    pub fn new(code: &[u8], cells: usize, extensions: Extensions) -> Self {
+        let (ops, source_map) = compile::compile_unoptimized(code, extensions);
+        let (_, optimized_map) = compile::compile_with_source_map(code, extensions);
+        let optimized = (0..source_map.len())
+            .map(|op| optimized_map.op_at(source_map.span(op).start).unwrap())
+            .collect();
+        let mut machine = Machine::new(ops, cells);
+        if let Some(input) = compile::bundled_input(code, extensions) {
+            machine.feed(input);
+            machine.close_input();
+        }
+        Self {
+            machine,
+            code: code.to_vec(),
+            lines: Lines::new(code),
+            source_map,
+            optimized,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Reads commands from `input` until it ends or `q` is entered, the program output goes to
+    /// `printer`
+    pub fn repl(
+        &mut self,
+        mut input: impl BufRead,
+        mut output: impl Write,
+        printer: &mut Printer,
+    ) -> anyhow::Result<()> {
+        writeln!(output, "{}", self.location())?;
+        loop {
+            write!(output, "(bfdb) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 || line.trim() == "q" {
+                return Ok(());
+            }
+            let reply = self
+                .command(line.trim(), printer)
+                .unwrap_or_else(|e| format!("error: {e}"));
+            writeln!(output, "{reply}")?;
+        }
+    }
+
+    /// Executes one command and returns the text to show
+    pub fn command(&mut self, line: &str, printer: &mut Printer) -> anyhow::Result<String> {
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+        let count = || -> anyhow::Result<usize> {
+            Ok(if argument.is_empty() {
+                1
+            } else {
+                argument.parse()?
+            })
+        };
+
+        match command {
+            "" | "l" => Ok(self.location()),
+            "h" | "help" => Ok(HELP.to_string()),
+            "s" => {
+                let mut steps = 0;
+                let count = count()?;
+                self.run(printer, |_| {
+                    steps += 1;
+                    steps > count
+                })
+            }
+            "o" => {
+                let mut count = count()?;
+                let optimized = self.optimized.clone();
+                let mut current = optimized.get(self.machine.ip()).copied();
+                self.run(printer, |machine| {
+                    let op = optimized.get(machine.ip()).copied();
+                    if op != current {
+                        count -= 1;
+                        current = op;
+                    }
+                    count == 0
+                })
+            }
+            "n" => match self.machine.ops().get(self.machine.ip()) {
+                Some(OpCode::JumpIfZero { .. }) => {
+                    let end = self.loop_end(self.machine.ip());
+                    self.run(printer, |machine| machine.ip() == end)
+                }
+                _ => self.command("s", printer),
+            },
+            "c" => self.run(printer, |_| false),
+            "b" => {
+                let op = self.op_at(argument)?;
+                self.breakpoints.insert(op);
+                Ok(format!("breakpoint at {}", self.describe(op)))
+            }
+            "d" => {
+                let op = self.op_at(argument)?;
+                match self.breakpoints.remove(&op) {
+                    true => Ok(format!("deleted breakpoint at {}", self.describe(op))),
+                    false => anyhow::bail!("no breakpoint at {}", self.describe(op)),
+                }
+            }
+            "w" => {
+                let cell: usize = argument.parse()?;
+                let value = *self
+                    .machine
+                    .cells()
+                    .get(cell)
+                    .ok_or_else(|| anyhow::anyhow!("cell {cell} is out of the tape"))?;
+                self.watchpoints.insert(cell, value);
+                Ok(format!("watching cell {cell} = {value}"))
+            }
+            "u" => {
+                let cell: usize = argument.parse()?;
+                match self.watchpoints.remove(&cell) {
+                    Some(_) => Ok(format!("removed watchpoint on cell {cell}")),
+                    None => anyhow::bail!("cell {cell} is not watched"),
+                }
+            }
+            "t" => Ok(self.tape(if argument.is_empty() {
+                8
+            } else {
+                argument.parse()?
+            })),
+            "i" => {
+                self.machine.feed(argument.as_bytes());
+                self.machine.feed(b"\n");
+                Ok(format!("fed {} bytes", argument.len() + 1))
+            }
+            "eof" => {
+                self.machine.close_input();
+                Ok("input closed".to_string())
+            }
+            _ => anyhow::bail!("unknown command `{command}`, `h` shows all commands"),
+        }
+    }
+
+    /// Runs until `done` holds before the next op, a breakpoint or watchpoint is hit or the machine
+    /// stops, returns the reason and the new location. Fails at an op which leaves the tape, the
+    /// machine stays before it.
+    fn run(
+        &mut self,
+        printer: &mut Printer,
+        mut done: impl FnMut(&Machine) -> bool,
+    ) -> anyhow::Result<String> {
+        let mut first = true;
+        let mut stop = None;
+        let breakpoints = &self.breakpoints;
+        let watchpoints = &mut self.watchpoints;
+        let status = self.machine.run_until(printer, |machine| {
+            for (cell, old) in watchpoints.iter_mut() {
+                let new = machine.cells()[*cell];
+                if new != *old {
+                    stop = Some(Stop::Watchpoint {
+                        cell: *cell,
+                        old: *old,
+                        new,
+                    });
+                    *old = new;
+                    return true;
+                }
+            }
+            // the breakpoint at the start location was already reported
+            if !first && breakpoints.contains(&machine.ip()) {
+                stop = Some(Stop::Breakpoint);
+                return true;
+            }
+            first = false;
+            done(machine)
+        });
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => anyhow::bail!("{e}\n{}", self.location()),
+        };
+
+        let mut out = String::new();
+        match stop {
+            Some(Stop::Breakpoint) => writeln!(out, "breakpoint").unwrap(),
+            Some(Stop::Watchpoint { cell, old, new }) => {
+                writeln!(out, "cell {cell} changed from {old} to {new}").unwrap()
+            }
+            None => match status {
+                Status::Ready => {}
+                Status::NeedsInput => {
+                    writeln!(out, "program needs input, feed it with `i` or `eof`").unwrap()
+                }
+                Status::Finished => writeln!(out, "program finished").unwrap(),
+            },
+        }
+        out.push_str(&self.location());
+        Ok(out)
+    }
+
+    /// Returns the index after the `]` matching the `[` at `open`
+    fn loop_end(&self, open: usize) -> usize {
+        match self.machine.ops()[open] {
+            OpCode::JumpIfZero { target } => target,
+            _ => unreachable!("{open} is no loop"),
+        }
+    }
+
+    /// Finds the op at `line[:column]`
+    fn op_at(&self, position: &str) -> anyhow::Result<usize> {
+        let (line, column) = match position.split_once(':') {
+            Some((line, column)) => (line.parse()?, column.parse()?),
+            None => (position.parse()?, 1),
+        };
+        self.lines
+            .offset(line, column)
+            .and_then(|offset| self.source_map.op_at(offset))
+            .ok_or_else(|| anyhow::anyhow!("no code at or after {line}:{column}"))
+    }
+
+    /// Source position and op at `op`
+    fn describe(&self, op: usize) -> String {
+        let (line, column) = self.lines.position(self.source_map.span(op).start);
+        format!("{line}:{column} ({:?})", self.machine.ops()[op])
+    }
+
+    /// Current position with the source line and a marker below the current character
+    pub fn location(&self) -> String {
+        let ip = self.machine.ip();
+        if ip >= self.machine.ops().len() {
+            return "at the end of the program".to_string();
+        }
+        let (line, column) = self.lines.position(self.source_map.span(ip).start);
+        let text = self.lines.line(line).unwrap();
+        format!(
+            "at {}, cell {}\n{}\n{}^",
+            self.describe(ip),
+            self.machine.cell(),
+            String::from_utf8_lossy(&self.code[text]),
+            " ".repeat(column - 1)
+        )
+    }
+
+    /// Cells within `radius` of the pointer, the current one in brackets
+    pub fn tape(&self, radius: usize) -> String {
+        let cells = self.machine.cells();
+        let current = self.machine.cell();
+        let start = current.saturating_sub(radius);
+        let end = current.saturating_add(radius + 1).min(cells.len());
+
+        let mut indices = String::from("cell ");
+        let mut values = String::from("value");
+        for (index, value) in cells.iter().enumerate().take(end).skip(start) {
+            let (open, close) = if index == current {
+                ('[', ']')
+            } else {
+                (' ', ' ')
+            };
+            write!(indices, " {open}{index:>5}{close}").unwrap();
+            write!(values, " {open}{value:>5}{close}").unwrap();
+        }
+        format!("{indices}\n{values}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use crate::Printer;
+
+    #[test]
+    fn debug_commands() {
+        let mut printer = Printer::new(|_| {});
+        let mut debugger = Debugger::new(b"++\n>+++[<+>-]\n<.", 16, Default::default());
+
+        debugger.command("s 2", &mut printer).unwrap();
+        assert_eq!(debugger.machine().cells()[0], 2);
+
+        debugger.command("b 2:5", &mut printer).unwrap();
+        debugger.command("c", &mut printer).unwrap();
+        assert_eq!(debugger.machine().ip(), 6);
+
+        debugger.command("n", &mut printer).unwrap();
+        assert_eq!(debugger.machine().cells()[..2], [5, 0]);
+
+        debugger.command("w 0", &mut printer).unwrap();
+        debugger.command("o", &mut printer).unwrap();
+        let reply = debugger.command("c", &mut printer).unwrap();
+        assert!(reply.starts_with("program finished"));
+        assert!(debugger
+            .command("t 1", &mut printer)
+            .unwrap()
+            .contains("[    0]"));
+
+        // leaving the tape is reported and the debugger stays at the prompt
+        let mut debugger = Debugger::new(b"+<+", 16, Default::default());
+        let mut output = Vec::new();
+        debugger
+            .repl(&b"c\nl\nq\n"[..], &mut output, &mut printer)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("error: the pointer moved left of the tape\nat 1:2"));
+        assert_eq!(output.matches("(bfdb) ").count(), 3);
+        assert_eq!(debugger.machine().ip(), 1);
+    }
+}
@@ -0,0 +1,61 @@
+use std::fmt::Write;
+
+use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter};
+
+use crate::{compile::OpCode, source::Source};
+
+/// Disassembles x86-64 `code` into Intel syntax.
+///
+/// `marks` are `(code offset, op index)` pairs sorted by offset. Every mark starts a new section
+/// headed by the `OpCode` it was generated from, `None` marks code which belongs to no op (e.g.
+/// the function prologue and epilogue). With a `source` the headers also show the line, column and
+/// byte offset the op comes from.
+pub fn disassemble(
+    code: &[u8],
+    ops: &[OpCode],
+    marks: &[(usize, Option<usize>)],
+    source: Option<&Source>,
+) -> String {
+    let mut out = String::new();
+    let mut decoder = Decoder::with_ip(64, code, 0, DecoderOptions::NONE);
+    let mut formatter = IntelFormatter::new();
+    let mut instruction = Instruction::default();
+    let mut text = String::new();
+    let mut marks = marks.iter().peekable();
+
+    while decoder.can_decode() {
+        let position = decoder.position();
+        let mut header = None;
+        while let Some((_, op)) = marks.next_if(|(offset, _)| *offset <= position) {
+            header = Some(*op);
+        }
+        match header {
+            Some(Some(index)) => match source {
+                Some(source) => writeln!(
+                    out,
+                    "; {index}: {:?} at {}, offset {}",
+                    ops[index],
+                    source.describe(index),
+                    source.map().span(index).start
+                ),
+                None => writeln!(out, "; {index}: {:?}", ops[index]),
+            }
+            .unwrap(),
+            Some(None) => writeln!(out, "; -").unwrap(),
+            None => {}
+        }
+
+        decoder.decode_out(&mut instruction);
+        text.clear();
+        formatter.format(&instruction, &mut text);
+
+        let bytes = code[position..position + instruction.len()]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(out, "{position:08x}  {bytes:<30} {text}").unwrap();
+    }
+
+    out
+}
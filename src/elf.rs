@@ -0,0 +1,155 @@
+use crate::{compile::OpCode, jit::jit};
+
+/// Virtual address at which the file is loaded
+const BASE: u64 = 0x40_0000;
+const PAGE: u64 = 0x1000;
+const ELF_HEADER_SIZE: u16 = 64;
+const PROGRAM_HEADER_SIZE: u16 = 56;
+const HEADERS_SIZE: usize = ELF_HEADER_SIZE as usize + 2 * PROGRAM_HEADER_SIZE as usize;
+
+/// Builds a static x86-64 linux executable from the code of [`jit`], without needing a linker.
+///
+/// Layout:
+/// text segment: elf header, program headers, `_start`, print, scan, program
+/// bss segment: tape of `cells` cells
+///
+/// Debug ops are skipped.
+pub fn elf(ops: &[OpCode], cells: usize) -> Vec<u8> {
+    // the executable has no budget to call the debug function through
+    let ops: Vec<_> = ops
+        .iter()
+        .copied()
+        .filter(|op| *op != OpCode::Debug)
+        .collect();
+    let program = jit(&ops);
+
+    let start = HEADERS_SIZE as u64;
+    let print = start + START_SIZE as u64;
+    let scan = print + PRINT_SIZE as u64;
+    let program_start = scan + SCAN_SIZE as u64;
+    let file_size = program_start + program.len() as u64;
+    let tape = file_size.div_ceil(PAGE) * PAGE + PAGE;
+
+    let mut file = Vec::with_capacity(file_size as usize);
+    file.extend(elf_header(BASE + start));
+    file.extend(program_header(PF_R | PF_X, 0, BASE, file_size, file_size));
+    file.extend(program_header(PF_R | PF_W, 0, BASE + tape, 0, cells as u64));
+    file.extend(start_stub(start, tape, print, scan, program_start));
+    file.extend(print_syscall());
+    file.extend(scan_syscall());
+    file.extend(program);
+    file
+}
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+fn elf_header(entry: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(ELF_HEADER_SIZE as usize);
+    header.extend(b"\x7fELF");
+    header.extend([
+        2, // 64 bit
+        1, // little endian
+        1, // elf version
+        0, // System V ABI
+    ]);
+    header.extend([0; 8]); // padding
+    header.extend(2u16.to_le_bytes()); // executable
+    header.extend(0x3eu16.to_le_bytes()); // x86-64
+    header.extend(1u32.to_le_bytes()); // elf version
+    header.extend(entry.to_le_bytes());
+    header.extend((ELF_HEADER_SIZE as u64).to_le_bytes()); // program header offset
+    header.extend(0u64.to_le_bytes()); // section header offset
+    header.extend(0u32.to_le_bytes()); // flags
+    header.extend(ELF_HEADER_SIZE.to_le_bytes());
+    header.extend(PROGRAM_HEADER_SIZE.to_le_bytes());
+    header.extend(2u16.to_le_bytes()); // program header count
+    header.extend(64u16.to_le_bytes()); // section header size
+    header.extend(0u16.to_le_bytes()); // section header count
+    header.extend(0u16.to_le_bytes()); // section name index
+    header
+}
+
+fn program_header(flags: u32, offset: u64, address: u64, file_size: u64, mem_size: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(PROGRAM_HEADER_SIZE as usize);
+    header.extend(1u32.to_le_bytes()); // PT_LOAD
+    header.extend(flags.to_le_bytes());
+    header.extend(offset.to_le_bytes());
+    header.extend(address.to_le_bytes()); // virtual address
+    header.extend(address.to_le_bytes()); // physical address
+    header.extend(file_size.to_le_bytes());
+    header.extend(mem_size.to_le_bytes());
+    header.extend(PAGE.to_le_bytes()); // alignment
+    header
+}
+
+const START_SIZE: usize = 35;
+
+/// Sets up the arguments of [`crate::JitFunc`] (printer and scanner objects are unused), calls the
+/// program and exits with 0
+fn start_stub(start: u64, tape: u64, print: u64, scan: u64, program: u64) -> [u8; START_SIZE] {
+    // rip relative displacement from the end of the instruction at `end` to `target`
+    let rel = |end: u64, target: u64| ((target as i64 - (start + end) as i64) as i32).to_le_bytes();
+    let tape = rel(7, tape);
+    let print = rel(14, print);
+    let scan = rel(21, scan);
+    let program = rel(26, program);
+    [
+        0x48, 0x8d, 0x3d, tape[0], tape[1], tape[2], tape[3], // lea rdi, [rip + tape]
+        0x48, 0x8d, 0x15, print[0], print[1], print[2], print[3], // lea rdx, [rip + print]
+        0x4c, 0x8d, 0x05, scan[0], scan[1], scan[2], scan[3], // lea r8, [rip + scan]
+        0xe8, program[0], program[1], program[2], program[3], // call program
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60 (exit)
+        0x31, 0xff, // xor edi, edi
+        0x0f, 0x05, // syscall
+    ]
+}
+
+const PRINT_SIZE: usize = 23;
+
+/// Writes the byte in rsi to stdout
+const fn print_syscall() -> [u8; PRINT_SIZE] {
+    [
+        0x56, // push rsi
+        0x48, 0x89, 0xe6, // mov rsi, rsp
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1 (stdout)
+        0xba, 0x01, 0x00, 0x00, 0x00, // mov edx, 1
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (write)
+        0x0f, 0x05, // syscall
+        0x5e, // pop rsi
+        0xc3, // ret
+    ]
+}
+
+const SCAN_SIZE: usize = 18;
+
+/// Reads one byte from stdin into al, al is 0 at the end of the input
+const fn scan_syscall() -> [u8; SCAN_SIZE] {
+    [
+        0x6a, 0x00, // push 0
+        0x48, 0x89, 0xe6, // mov rsi, rsp
+        0x31, 0xff, // xor edi, edi (stdin)
+        0xba, 0x01, 0x00, 0x00, 0x00, // mov edx, 1
+        0x31, 0xc0, // xor eax, eax (read)
+        0x0f, 0x05, // syscall
+        0x58, // pop rax
+        0xc3, // ret
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HEADERS_SIZE, PRINT_SIZE, SCAN_SIZE, START_SIZE};
+    use crate::{compile, jit::jit};
+
+    #[test]
+    fn code_elf() {
+        let ops = compile::compile(b",++++++++++.");
+        let file = super::elf(&ops, 30000);
+
+        assert!(file.starts_with(b"\x7fELF"));
+        let code_size = START_SIZE + PRINT_SIZE + SCAN_SIZE + jit(&ops).len();
+        assert_eq!(file.len(), HEADERS_SIZE + code_size);
+    }
+}
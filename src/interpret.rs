@@ -1,18 +1,62 @@
 use crate::{
-    compile::OpCode, printer_function, scanner_function, Measured, Printer, Runner, Scanner,
+    compile::OpCode,
+    dump_tape, printer_function,
+    runtime::{Budget, Instrumentation},
+    scanner_function, Measured, Options, Printer, RunError, Runner, Scanner,
 };
 
 pub struct Interpreter;
 
 impl Interpreter {
-    fn run(ops: &mut [OpCode], cells: &mut [u8], printer: &mut Printer, scanner: &mut Scanner) {
+    fn run(
+        ops: &mut [OpCode],
+        cells: &mut Vec<u8>,
+        printer: &mut Printer,
+        scanner: &mut Scanner,
+        options: &Options,
+    ) -> Result<(), RunError> {
+        let mut budget = Budget::for_run(options, ops, cells)?;
+        let instrumentation = Instrumentation::new(options);
+        // the loop without the budget has no cost per op for instrumentation which is off
+        let cell = if instrumentation.uses_budget() {
+            Self::exec_ops::<true>(
+                ops,
+                cells,
+                printer,
+                scanner,
+                &mut budget,
+                instrumentation.grow,
+            )
+        } else {
+            Self::exec_ops::<false>(ops, cells, printer, scanner, &mut budget, false)
+        };
+        budget.result(cell)
+    }
+
+    /// Executes the ops from the start cell of the `budget` on, which are only traced, counted,
+    /// stepped and grow the tape if `grow` is set if `INSTRUMENTED` is set, returns the cell index
+    /// the run ended or stopped at
+    fn exec_ops<const INSTRUMENTED: bool>(
+        ops: &[OpCode],
+        cells: &mut Vec<u8>,
+        printer: &mut Printer,
+        scanner: &mut Scanner,
+        budget: &mut Budget,
+        grow: bool,
+    ) -> usize {
         let mut ip = 0usize;
-        let mut cell = 0usize;
+        let mut cell = budget.cell;
 
         while ip < ops.len() {
+            if INSTRUMENTED {
+                budget.run.trace(ip, cells, cell);
+            }
             match ops[ip] {
                 OpCode::Right { count } => {
                     cell += count as usize;
+                    if INSTRUMENTED && grow && budget.grow(cells, cell, ip) {
+                        break;
+                    }
                     ip += 1;
                 }
                 OpCode::Left { count } => {
@@ -21,11 +65,17 @@ impl Interpreter {
                 }
                 OpCode::Inc { count, offset } => {
                     let cell = (cell as i32 + offset) as usize;
+                    if INSTRUMENTED && grow && budget.grow(cells, cell, ip) {
+                        break;
+                    }
                     cells[cell] = cells[cell].wrapping_add(count);
                     ip += 1;
                 }
                 OpCode::Dec { count, offset } => {
                     let cell = (cell as i32 + offset) as usize;
+                    if INSTRUMENTED && grow && budget.grow(cells, cell, ip) {
+                        break;
+                    }
                     cells[cell] = cells[cell].wrapping_sub(count);
                     ip += 1;
                 }
@@ -38,9 +88,18 @@ impl Interpreter {
                     ip += 1;
                 }
                 OpCode::JumpIfZero { target } => {
+                    if INSTRUMENTED {
+                        budget.run.count(ip);
+                    }
                     ip = if cells[cell] == 0 { target } else { ip + 1 };
                 }
                 OpCode::JumpIfNotZero { target } => {
+                    if INSTRUMENTED {
+                        if budget.step(ip) {
+                            break;
+                        }
+                        budget.run.count(ip);
+                    }
                     ip = if cells[cell] != 0 { target } else { ip + 1 };
                 }
                 OpCode::SetZero => {
@@ -49,40 +108,54 @@ impl Interpreter {
                 }
                 OpCode::Mul { factor, offset } => {
                     let off_cell = (cell as i32 + offset) as usize;
+                    if INSTRUMENTED && grow && budget.grow(cells, off_cell, ip) {
+                        break;
+                    }
 
-                    cells[off_cell] += cells[cell].wrapping_mul(factor);
+                    cells[off_cell] =
+                        cells[off_cell].wrapping_add(cells[cell].wrapping_mul(factor));
                     cells[cell] = 0;
                     ip += 1;
                 }
+                OpCode::Debug => {
+                    dump_tape(cells, cell);
+                    ip += 1;
+                }
             }
         }
+        cell
     }
 }
 
 impl Runner for Interpreter {
-    fn exec(ops: &mut [OpCode], cells: &mut [u8], printer: &mut Printer, scanner: &mut Scanner) {
+    fn exec(
+        ops: &mut [OpCode],
+        cells: &mut Vec<u8>,
+        printer: &mut Printer,
+        scanner: &mut Scanner,
+        options: &Options,
+    ) -> Result<(), RunError> {
         back_patch(ops);
-        Interpreter::run(ops, cells, printer, scanner);
+        Interpreter::run(ops, cells, printer, scanner, options)
     }
 
     fn exec_bench(
         ops: &mut [OpCode],
-        cells: &mut [u8],
+        cells: &mut Vec<u8>,
         printer: &mut Printer,
         scanner: &mut Scanner,
         count: usize,
-    ) -> Measured<()> {
+        options: &Options,
+    ) -> Result<Measured<()>, RunError> {
         let mut m = Measured::new();
         m.measure("back patching", || back_patch(ops));
-        for i in 0..count {
-            m.measure(format!("interpret {i}"), || {
-                Interpreter::run(ops, cells, printer, scanner)
-            })
+        for _ in 0..count {
+            m.measure_run(|| Interpreter::run(ops, cells, printer, scanner, options))?;
         }
-        m
+        Ok(m)
     }
 }
-fn back_patch(ops: &mut [OpCode]) {
+pub(crate) fn back_patch(ops: &mut [OpCode]) {
     let mut open: Vec<usize> = Vec::new();
     let mut current = 0usize;
 
@@ -120,7 +193,10 @@ fn back_patch(ops: &mut [OpCode]) {
 
 #[cfg(test)]
 mod tests {
-    use crate::{compile, Printer, Runner, Scanner};
+    use crate::{
+        cljit::ClJit, compile, jit::Jit, limit::Limits, threaded::Threaded, tiered::Tiered,
+        Options, Printer, RunError, Runner, Scanner, State,
+    };
 
     use super::Interpreter;
 
@@ -134,6 +210,74 @@ mod tests {
         let mut scanner = Scanner::new(|| 12);
         let mut cells = vec![0u8; 30000];
 
-        Interpreter::exec(&mut ops, &mut cells, &mut printer, &mut scanner);
+        Interpreter::exec(
+            &mut ops,
+            &mut cells,
+            &mut printer,
+            &mut scanner,
+            &Options::default(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn start_cell() {
+        fn tape<T: Runner>(code: &[u8]) -> Vec<u8> {
+            let mut ops = compile::compile(code);
+            let mut cells = vec![0u8; 8];
+            let options = Options {
+                start_cell: 4,
+                ..Options::default()
+            };
+            T::exec(
+                &mut ops,
+                &mut cells,
+                &mut Printer::new(|_| {}),
+                &mut Scanner::new(|| 0),
+                &options,
+            )
+            .unwrap();
+            cells
+        }
+
+        let code = b"<<+++[>++<-]>>-";
+        let expected = vec![0, 0, 0, 6, 255, 0, 0, 0];
+        assert_eq!(tape::<Interpreter>(code), expected);
+        assert_eq!(tape::<Threaded>(code), expected);
+        assert_eq!(tape::<Tiered>(code), expected);
+        assert_eq!(tape::<Jit>(code), expected);
+        assert_eq!(tape::<ClJit>(code), expected);
+
+        // a start out of the tape is rejected before any code runs
+        fn out_of_tape<T: Runner>(grow_tape: bool) -> Result<(), RunError> {
+            let options = Options {
+                start_cell: 8,
+                grow_tape,
+                limits: Limits {
+                    cells: Some(8),
+                    ..Limits::default()
+                },
+                ..Options::default()
+            };
+            T::exec(
+                &mut compile::compile(b"+"),
+                &mut vec![0u8; 8],
+                &mut Printer::new(|_| {}),
+                &mut Scanner::new(|| 0),
+                &options,
+            )
+        }
+        let stopped = Err(RunError::TapeLimit(State {
+            cell: 8,
+            op: 0,
+            steps: 0,
+        }));
+        for grow_tape in [false, true] {
+            assert_eq!(out_of_tape::<Interpreter>(grow_tape), stopped);
+            assert_eq!(out_of_tape::<Threaded>(grow_tape), stopped);
+            assert_eq!(out_of_tape::<Tiered>(grow_tape), stopped);
+            assert_eq!(out_of_tape::<Jit>(grow_tape), stopped);
+            assert_eq!(out_of_tape::<ClJit>(grow_tape), stopped);
+        }
     }
 }
@@ -1,67 +1,117 @@
 use memmap2::Mmap;
 
-use crate::{compile::OpCode, printer_function, scanner_function, JitFunc, Measured, Runner};
+use crate::{
+    compile::OpCode,
+    disasm, printer_function,
+    runtime::{self, Budget, Instrumentation},
+    scanner_function,
+    source::Source,
+    JitFunc, Measured, Options, RunError, Runner,
+};
 
 pub struct Jit {
     program: Mmap,
+    /// ops of the program, for the profile and the trace
+    ops: Vec<OpCode>,
 }
 
 impl Jit {
-    fn compile(ops: &[OpCode]) -> Self {
-        let code = jit(ops);
+    fn compile(ops: &[OpCode], options: &Options) -> Self {
+        let instrumentation = Instrumentation::new(options);
+        if instrumentation != Instrumentation::default() {
+            return Self::load(&jit_with_marks(ops, instrumentation).0, ops);
+        }
+        let Some(entry) = &options.cache else {
+            return Self::load(&jit(ops), ops);
+        };
+        let code = entry.load_code().unwrap_or_else(|| {
+            let code = jit(ops);
+            entry.store_code(&code);
+            code
+        });
+        Self::load(&code, ops)
+    }
+
+    fn load(code: &[u8], ops: &[OpCode]) -> Self {
         let mut map = memmap2::MmapMut::map_anon(code.len()).unwrap();
-        map.copy_from_slice(&code);
+        map.copy_from_slice(code);
         Self {
             program: map.make_exec().unwrap(),
+            ops: ops.to_vec(),
         }
     }
 
+    /// Disassembles the code generated for `ops`, annotated with the op each instruction belongs to
+    /// and its position in the `source`
+    pub fn disassemble(ops: &[OpCode], source: Option<&Source>) -> String {
+        let (code, marks) = jit_with_marks(ops, Instrumentation::default());
+        disasm::disassemble(&code, ops, &marks, source)
+    }
+
     fn get_func(&self) -> JitFunc {
         unsafe { std::mem::transmute(self.program.as_ptr()) }
     }
 
-    fn run(&self, cells: &mut [u8], printer: &mut crate::Printer, scanner: &mut crate::Scanner) {
+    fn run(
+        &self,
+        cells: &mut Vec<u8>,
+        printer: &mut crate::Printer,
+        scanner: &mut crate::Scanner,
+        options: &Options,
+    ) -> Result<(), RunError> {
         let printer = printer as *mut crate::Printer;
         let scanner = scanner as *mut crate::Scanner;
-        let cells = cells.as_mut_ptr();
+        let mut budget = Budget::for_run(options, &self.ops, cells)?;
+        let cells = budget.tape_ptr(cells);
 
         let func = self.get_func();
-        func(cells, printer, printer_function, scanner, scanner_function);
+        func(
+            cells,
+            printer,
+            printer_function,
+            scanner,
+            scanner_function,
+            &mut budget as *mut Budget as *mut u8,
+        );
+        budget.result(budget.cell)
     }
 }
 
 impl Runner for Jit {
     fn exec(
         ops: &mut [OpCode],
-        cells: &mut [u8],
+        cells: &mut Vec<u8>,
         printer: &mut crate::Printer,
         scanner: &mut crate::Scanner,
-    ) {
-        Jit::compile(ops).run(cells, printer, scanner);
+        options: &Options,
+    ) -> Result<(), RunError> {
+        Jit::compile(ops, options).run(cells, printer, scanner, options)
     }
 
     fn exec_bench(
         ops: &mut [OpCode],
-        cells: &mut [u8],
+        cells: &mut Vec<u8>,
         printer: &mut crate::Printer,
         scanner: &mut crate::Scanner,
         count: usize,
-    ) -> Measured<()> {
+        options: &Options,
+    ) -> Result<Measured<()>, RunError> {
         let mut m = Measured::new();
 
-        let j = Jit::compile(ops);
+        let j = Jit::compile(ops, options);
 
-        for i in 0..count {
-            m.measure(format!("run {i}"), || j.run(cells, printer, scanner));
+        for _ in 0..count {
+            m.measure_run(|| j.run(cells, printer, scanner, options))?;
         }
 
-        m
+        Ok(m)
     }
 }
 
 /// Registers:
 /// rdi: cells array
 /// rbx: current cell
+/// r9: step budget, only used by the instrumentation and debug ops
 const fn move_cell_right(count: u32) -> [u8; 6] {
     let count = count.to_ne_bytes();
     // add ebx, dword <count>
@@ -98,6 +148,17 @@ const fn init() -> [u8; 4] {
     ]
 }
 
+/// [`init`] which starts at the cell stored in the budget in r9
+const fn init_from_budget() -> [u8; 5] {
+    [
+        0x53, // push rbx
+        0x49,
+        0x8b,
+        0x59,
+        runtime::CELL_OFFSET as u8, // mov rbx, [r9 + cell]
+    ]
+}
+
 /// this is only the opcode, back patching is needed
 const fn jump_if_zero() -> [u8; 11] {
     [
@@ -130,17 +191,19 @@ const fn finish() -> [u8; 2] {
     ]
 }
 
-const fn print_current_cell() -> [u8; 25] {
+const fn print_current_cell() -> [u8; 29] {
     [
         0x57, // push   rdi
         0x56, // push   rsi
         0x52, // push   rdx
         0x51, // push   rcx
         0x41, 0x50, // push   r8
+        0x41, 0x51, // push   r9
         0x48, 0x89, 0xf0, // mov    rax,rsi
         0x48, 0x0f, 0xb6, 0x34, 0x1f, // movzx  rsi,BYTE PTR [rdi+rbx*1]
         0x48, 0x89, 0xc7, // mov rdi, rax
         0xff, 0xd2, // call  rdx
+        0x41, 0x59, // pop    r9
         0x41, 0x58, // pop    r8
         0x59, // pop    rcx
         0x5a, // pop    rdx
@@ -149,15 +212,17 @@ const fn print_current_cell() -> [u8; 25] {
     ]
 }
 
-const fn scan_current_cell() -> [u8; 21] {
+const fn scan_current_cell() -> [u8; 25] {
     [
         0x57, // push   rdi
         0x56, // push   rsi
         0x52, // push   rdx
         0x51, // push   rcx
         0x41, 0x50, // push   r8
+        0x41, 0x51, // push   r9
         0x48, 0x89, 0xcf, // mov    rdi,rcx
         0x41, 0xff, 0xd0, // call   r8
+        0x41, 0x59, // pop    r9
         0x41, 0x58, // pop    r8
         0x59, // pop    rcx
         0x5a, // pop    rdx
@@ -167,6 +232,176 @@ const fn scan_current_cell() -> [u8; 21] {
     ]
 }
 
+/// Counts one step of the budget in r9 at the `]` with index `op`, this is only the opcode, the
+/// jump to [`stop`] needs back patching
+const fn step(op: u32) -> [u8; 44] {
+    let check = runtime::CHECK_OFFSET as u8;
+    let op_offset = runtime::OP_OFFSET as u8;
+    let op = op.to_ne_bytes();
+    [
+        0x49, 0xff, 0x09, // dec qword [r9]
+        0x75, 0x27, // jnz over the check
+        0x49, 0xc7, 0x41, op_offset, op[0], op[1], op[2], op[3], // mov qword [r9 + op], <op>
+        0x57,  // push   rdi
+        0x56,  // push   rsi
+        0x52,  // push   rdx
+        0x51,  // push   rcx
+        0x41, 0x50, // push   r8
+        0x41, 0x51, // push   r9
+        0x4c, 0x89, 0xcf, // mov rdi, r9
+        0x41, 0xff, 0x51, check, // call [r9 + check]
+        0x41, 0x59, // pop    r9
+        0x41, 0x58, // pop    r8
+        0x59, // pop    rcx
+        0x5a, // pop    rdx
+        0x5e, // pop    rsi
+        0x5f, // pop    rdi
+        0x84, 0xc0, // test al, al
+        0x0f, 0x85, 0x00, 0x00, 0x00, 0x00, // jump to stop if the budget is used up
+    ]
+}
+
+/// Calls the debug function of the budget in r9 with the tape and the current cell
+const fn debug() -> [u8; 29] {
+    [
+        0x57, // push   rdi
+        0x56, // push   rsi
+        0x52, // push   rdx
+        0x51, // push   rcx
+        0x41,
+        0x50, // push   r8
+        0x41,
+        0x51, // push   r9
+        0x48,
+        0x89,
+        0xfe, // mov rsi, rdi
+        0x48,
+        0x89,
+        0xda, // mov rdx, rbx
+        0x4c,
+        0x89,
+        0xcf, // mov rdi, r9
+        0x41,
+        0xff,
+        0x51,
+        runtime::DEBUG_OFFSET as u8, // call [r9 + debug]
+        0x41,
+        0x59, // pop    r9
+        0x41,
+        0x58, // pop    r8
+        0x59, // pop    rcx
+        0x5a, // pop    rdx
+        0x5e, // pop    rsi
+        0x5f, // pop    rdi
+    ]
+}
+
+/// Counts one execution of the `[` or `]` with index `op` in the loop counts of the budget in r9
+const fn count(op: u32) -> [u8; 11] {
+    let offset = (op * 8).to_ne_bytes();
+    [
+        0x49,
+        0x8b,
+        0x41,
+        runtime::LOOP_COUNTS_OFFSET as u8, // mov rax, [r9 + loop counts]
+        0x48,
+        0xff,
+        0x80,
+        offset[0],
+        offset[1],
+        offset[2],
+        offset[3], // inc qword [rax + 8 * op]
+    ]
+}
+
+/// Calls the trace function of the budget in r9 with the tape, the current cell and `op`
+const fn trace(op: u32) -> [u8; 34] {
+    let op = op.to_ne_bytes();
+    [
+        0x57, // push   rdi
+        0x56, // push   rsi
+        0x52, // push   rdx
+        0x51, // push   rcx
+        0x41,
+        0x50, // push   r8
+        0x41,
+        0x51, // push   r9
+        0x48,
+        0x89,
+        0xfe, // mov rsi, rdi
+        0x48,
+        0x89,
+        0xda, // mov rdx, rbx
+        0xb9,
+        op[0],
+        op[1],
+        op[2],
+        op[3], // mov ecx, <op>
+        0x4c,
+        0x89,
+        0xcf, // mov rdi, r9
+        0x41,
+        0xff,
+        0x51,
+        runtime::TRACE_OFFSET as u8, // call [r9 + trace]
+        0x41,
+        0x59, // pop    r9
+        0x41,
+        0x58, // pop    r8
+        0x59, // pop    rcx
+        0x5a, // pop    rdx
+        0x5e, // pop    rsi
+        0x5f, // pop    rdi
+    ]
+}
+
+/// Grows the tape through the budget in r9 if the cell `offset` cells right of the current one is
+/// not in it, the new tape replaces the one in rdi. This is only the opcode, the jump to [`stop`]
+/// if the tape can not grow at the op with index `op` needs back patching.
+const fn grow(offset: i32, op: u32) -> [u8; 56] {
+    let tape = runtime::TAPE_OFFSET as u8;
+    let grow = runtime::GROW_OFFSET as u8;
+    let offset = offset.to_ne_bytes();
+    let op = op.to_ne_bytes();
+    [
+        0x48, 0x8d, 0x83, offset[0], offset[1], offset[2],
+        offset[3], // lea rax, [rbx + <offset>]
+        0x49, 0x3b, 0x41, tape, // cmp rax, [r9 + tape]
+        0x72, 0x2b, // jb over the call
+        0x57, // push   rdi
+        0x56, // push   rsi
+        0x52, // push   rdx
+        0x51, // push   rcx
+        0x41, 0x50, // push   r8
+        0x41, 0x51, // push   r9
+        0x48, 0x89, 0xc6, // mov rsi, rax
+        0xba, op[0], op[1], op[2], op[3], // mov edx, <op>
+        0x4c, 0x89, 0xcf, // mov rdi, r9
+        0x41, 0xff, 0x51, grow, // call [r9 + grow]
+        0x41, 0x59, // pop    r9
+        0x41, 0x58, // pop    r8
+        0x59, // pop    rcx
+        0x5a, // pop    rdx
+        0x5e, // pop    rsi
+        0x5f, // pop    rdi
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x48, 0x85, 0xc0, // test rax, rax
+        0x0f, 0x84, 0x00, 0x00, 0x00, 0x00, // jump to stop if the tape did not grow
+    ]
+}
+
+/// Stores the current cell in the budget and returns
+const fn stop() -> [u8; 6] {
+    [
+        0x49,
+        0x89,
+        0x59,
+        runtime::CELL_OFFSET as u8, // mov [r9 + cell], rbx
+        0x5b,                       // pop rbx
+        0xc3,                       // ret
+    ]
+}
+
 const fn mul(factor: u8, offset: i32) -> [u8; 20] {
     let offset = offset.to_ne_bytes();
     [
@@ -178,14 +413,53 @@ const fn mul(factor: u8, offset: i32) -> [u8; 20] {
     ]
 }
 
-fn jit(ops: &[OpCode]) -> Vec<u8> {
+/// Version of the code returned by [`jit`] and of the [`JitFunc`] it implements, the cache keys
+/// the code by it, so it has to change with either of them
+pub(crate) const CODE_VERSION: u32 = 1;
+
+pub(crate) fn jit(ops: &[OpCode]) -> Vec<u8> {
+    jit_with_marks(ops, Instrumentation::default()).0
+}
+
+/// returns the code and the code offset at which each op starts, the `instrumentation` uses the
+/// budget in r9
+fn jit_with_marks(
+    ops: &[OpCode],
+    instrumentation: Instrumentation,
+) -> (Vec<u8>, Vec<(usize, Option<usize>)>) {
     let mut back_patch_stack: Vec<usize> = Vec::new();
+    // locations of the jumps to the stop code
+    let mut stops: Vec<usize> = Vec::new();
     let mut code: Vec<u8> = Vec::new();
-    code.extend(init());
-    for op in ops {
+    let mut marks = vec![(0, None)];
+    if instrumentation.start {
+        code.extend(init_from_budget());
+    } else {
+        code.extend(init());
+    }
+    for (index, op) in ops.iter().enumerate() {
+        marks.push((code.len(), Some(index)));
+        if instrumentation.trace {
+            code.extend(trace(index as u32));
+        }
+        match op {
+            OpCode::Inc { offset, .. }
+            | OpCode::Dec { offset, .. }
+            | OpCode::Mul { offset, .. }
+                if instrumentation.grow && *offset > 0 =>
+            {
+                code.extend(grow(*offset, index as u32));
+                stops.push(code.len());
+            }
+            _ => {}
+        }
         match op {
             OpCode::Right { count } => {
                 code.extend(move_cell_right(*count));
+                if instrumentation.grow {
+                    code.extend(grow(0, index as u32));
+                    stops.push(code.len());
+                }
             }
             OpCode::Left { count } => {
                 code.extend(move_cell_left(*count));
@@ -203,11 +477,21 @@ fn jit(ops: &[OpCode]) -> Vec<u8> {
                 code.extend(scan_current_cell());
             }
             OpCode::JumpIfZero { .. } => {
+                if instrumentation.loops {
+                    code.extend(count(index as u32));
+                }
                 code.extend(jump_if_zero());
                 // push the location of the jump target on the back patch stack
                 back_patch_stack.push(code.len());
             }
             OpCode::JumpIfNotZero { .. } => {
+                if instrumentation.steps {
+                    code.extend(step(index as u32));
+                    stops.push(code.len());
+                }
+                if instrumentation.loops {
+                    code.extend(count(index as u32));
+                }
                 code.extend(jump_if_not_zero());
                 let target = back_patch_stack.pop().expect("Closing ] without [");
                 let offset = code.len() - target;
@@ -228,16 +512,27 @@ fn jit(ops: &[OpCode]) -> Vec<u8> {
             OpCode::Mul { factor, offset } => {
                 code.extend(mul(*factor, *offset));
             }
+            OpCode::Debug => {
+                code.extend(debug());
+            }
         }
     }
 
+    marks.push((code.len(), None));
     code.extend(finish());
-    code
+    if !stops.is_empty() {
+        for jump in stops {
+            let bytes = ((code.len() - jump) as u32).to_ne_bytes();
+            code[jump - 4..jump].copy_from_slice(&bytes);
+        }
+        code.extend(stop());
+    }
+    (code, marks)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{compile, Printer, Runner, Scanner};
+    use crate::{compile, source::Source, Options, Printer, Runner, Scanner};
 
     use super::Jit;
 
@@ -251,6 +546,23 @@ mod tests {
         let mut scanner = Scanner::new(|| 12);
         let mut cells = vec![0u8; 30000];
 
-        Jit::exec(&mut ops, &mut cells, &mut printer, &mut scanner);
+        Jit::exec(
+            &mut ops,
+            &mut cells,
+            &mut printer,
+            &mut scanner,
+            &Options::default(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn code_disassemble() {
+        let (ops, map) = compile::compile_with_source_map(b"+[-].", Default::default());
+        let asm = Jit::disassemble(&ops, Some(&Source::new(b"+[-].", map)));
+
+        assert!(asm.contains("; 1: SetZero at 1:2 `[-]`, offset 1"));
+        assert!(asm.contains("mov byte ptr [rdi+rbx],0"));
+        assert!(asm.trim_end().ends_with("ret"));
     }
 }
@@ -1,20 +1,146 @@
+pub mod aot;
+pub mod c;
+pub mod cache;
 pub mod cljit;
 pub mod compile;
+pub mod coverage;
+pub mod debug;
+pub mod disasm;
+pub mod elf;
 pub mod interpret;
 pub mod jit;
+pub mod limit;
+pub mod llvm;
+pub mod machine;
 pub mod meassure;
+pub mod profile;
+pub mod runtime;
+pub mod rust;
+pub mod source;
+pub mod threaded;
+pub mod tiered;
+pub mod trace;
+pub mod wasm;
 use compile::OpCode;
 use meassure::Measured;
 use std::io::{stdin, stdout, BufRead, Write};
 
-pub fn run<T: Runner>(code: &[u8], cells: usize) {
-    let mut ops = compile::compile(code);
+/// Options which are passed to every [`Runner`]
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Code generator settings of [`cljit::ClJit`]
+    pub cranelift: cljit::Settings,
+    /// Cache entry of the program, used to skip compiling and code generation
+    pub cache: Option<cache::Entry>,
+    /// Step and time limits and cancellation of each run
+    pub limits: limit::Limits,
+    /// Language extensions used to compile the source
+    pub extensions: compile::Extensions,
+    /// Counts the loop executions of every run
+    pub profile: Option<profile::Profiler>,
+    /// Traces the executed ops of every run
+    pub trace: Option<trace::Tracer>,
+    /// Records the tape usage of every run, the report is printed to stderr if a run panics
+    pub coverage: Option<coverage::Coverage>,
+    /// Index of the cell the pointer starts at, programs can move left of it
+    pub start_cell: usize,
+    /// Grows the tape once a cell right of its end is used, instead of running out of it
+    pub grow_tape: bool,
+}
+
+/// State of a run which was stopped before the end of the program.
+///
+/// The output up to this point was already passed to the [`Printer`] and the tape is left as it
+/// was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct State {
+    /// Index of the current cell
+    pub cell: usize,
+    /// Index of the op at which the run stopped, a `]` unless the tape reached its limit
+    pub op: usize,
+    /// Number of executed `]`
+    pub steps: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunError {
+    /// The run exceeded [`limit::Limits::steps`]
+    StepLimit(State),
+    /// The run exceeded [`limit::Limits::timeout`]
+    Timeout(State),
+    /// [`limit::Limits::cancel`] was cancelled during the run
+    Cancelled(State),
+    /// The tape would grow beyond [`limit::Limits::cells`] or its memory could not be allocated
+    TapeLimit(State),
+    /// The runner could not compile the code at [`State::op`], e.g. an unmatched bracket in
+    /// [`threaded::Threaded`] or the hot loop starting there in [`tiered::Tiered`]
+    Compile(State, String),
+}
+
+impl RunError {
+    pub fn state(&self) -> &State {
+        match self {
+            RunError::StepLimit(state)
+            | RunError::Timeout(state)
+            | RunError::Cancelled(state)
+            | RunError::TapeLimit(state)
+            | RunError::Compile(state, _) => state,
+        }
+    }
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (reason, state) = match self {
+            RunError::StepLimit(state) => ("step limit reached", state),
+            RunError::Timeout(state) => ("timed out", state),
+            RunError::Cancelled(state) => ("cancelled", state),
+            // code without step limits does not count the steps
+            RunError::TapeLimit(state) => {
+                return write!(f, "tape limit reached at cell {}", state.cell)
+            }
+            RunError::Compile(state, message) => {
+                return write!(f, "could not compile op {}: {message}", state.op)
+            }
+        };
+        write!(
+            f,
+            "{reason} after {} steps at cell {}",
+            state.steps, state.cell
+        )
+    }
+}
+
+impl std::error::Error for RunError {}
+
+/// Runs brainfuck source or bytecode
+pub fn run<T: Runner>(code: &[u8], cells: usize, options: &Options) -> anyhow::Result<()> {
+    let mut ops = match options.cache.as_ref().and_then(|entry| entry.load_ops()) {
+        Some(ops) => ops,
+        None => {
+            let ops = compile::compile_or_load(code, options.extensions)?;
+            if let Some(entry) = &options.cache {
+                entry.store_ops(&ops);
+            }
+            ops
+        }
+    };
     let mut cells = vec![0u8; cells];
 
+    let input = compile::bundled_input(code, options.extensions);
     let mut printer = make_printer();
-    let mut scanner = make_scanner();
+    let mut scanner = make_scanner(input);
 
-    T::exec(&mut ops, &mut cells, &mut printer, &mut scanner)
+    if let Err(error) = T::exec(&mut ops, &mut cells, &mut printer, &mut scanner, options) {
+        if compile::is_bytecode(code) {
+            return Err(error.into());
+        }
+        // only compiled again on errors, so runs without them need no source map
+        let (_, map) = compile::compile_with_source_map(code, options.extensions);
+        let position = source::Source::new(code, map).describe(error.state().op);
+        return Err(anyhow::Error::new(error).context(format!("stopped at {position}")));
+    }
+    Ok(())
 }
 
 pub fn make_printer() -> Printer {
@@ -26,7 +152,14 @@ pub fn make_printer() -> Printer {
     Printer::new(print)
 }
 
-pub fn make_scanner() -> Scanner {
+/// Reads from stdin, or only from `input` if the program was bundled with its input, the end of
+/// the input reads as 0
+pub fn make_scanner(input: Option<&[u8]>) -> Scanner {
+    if let Some(input) = input {
+        let input = input.to_vec();
+        let mut input = input.into_iter();
+        return Scanner::new(move || input.next().unwrap_or(0));
+    }
     let mut buffer = Vec::new();
     let input = stdin();
     let mut input = input.lock();
@@ -44,18 +177,29 @@ pub fn make_scanner() -> Scanner {
 }
 
 pub trait Runner {
-    fn exec(ops: &mut [OpCode], cells: &mut [u8], printer: &mut Printer, scanner: &mut Scanner);
+    fn exec(
+        ops: &mut [OpCode],
+        cells: &mut Vec<u8>,
+        printer: &mut Printer,
+        scanner: &mut Scanner,
+        options: &Options,
+    ) -> Result<(), RunError>;
 
     fn exec_bench(
         ops: &mut [OpCode],
-        cells: &mut [u8],
+        cells: &mut Vec<u8>,
         printer: &mut Printer,
         scanner: &mut Scanner,
         count: usize,
-    ) -> Measured<()>;
+        options: &Options,
+    ) -> Result<Measured<()>, RunError>;
 }
 
-pub type JitFunc = fn(*mut u8, *mut Printer, PrinterFunc, *mut Scanner, ScannerFunc);
+/// Signature of the generated programs, which read their arguments from the System V registers.
+///
+/// The last argument points to the step budget, it is only used by code generated with limits
+pub type JitFunc =
+    extern "C" fn(*mut u8, *mut Printer, PrinterFunc, *mut Scanner, ScannerFunc, *mut u8);
 
 pub struct Printer {
     printer: Box<dyn FnMut(u8)>,
@@ -93,3 +237,131 @@ pub extern "C" fn scanner_function(scanner: &mut Scanner) -> u8 {
 }
 
 pub type ScannerFunc = extern "C" fn(&mut Scanner) -> u8;
+
+/// Prints the pointer and the cells around it to stderr, executes [`OpCode::Debug`]
+pub fn dump_tape(cells: &[u8], cell: usize) {
+    const RADIUS: usize = 8;
+    let end = cell.saturating_add(RADIUS + 1).min(cells.len());
+    let start = cell.saturating_sub(RADIUS).min(end);
+
+    let mut out = format!("# pointer {cell}, cells {start}..{end}:");
+    for (index, value) in cells[start..end].iter().enumerate() {
+        if start + index == cell {
+            out.push_str(&format!(" [{value}]"));
+        } else {
+            out.push_str(&format!(" {value}"));
+        }
+    }
+    eprintln!("{out}");
+}
+
+/// Grows `cells` so that `cell` is in the tape, the tape at least doubles so growing often stays
+/// cheap, but it never grows beyond `max` cells. Returns false if `cell` does not fit into `max`
+/// cells or the memory can not be allocated.
+pub(crate) fn grow_tape(cells: &mut Vec<u8>, cell: usize, max: usize) -> bool {
+    if cell < cells.len() {
+        return true;
+    }
+    if cell >= max {
+        return false;
+    }
+    let len = (cell + 1).max(cells.len().saturating_mul(2)).min(max);
+    if cells.try_reserve_exact(len - cells.len()).is_err() {
+        return false;
+    }
+    cells.resize(len, 0);
+    true
+}
+
+/// [`dump_tape`] for generated code, which only knows the tape length through the budget
+pub(crate) extern "C" fn debug_function(budget: &runtime::Budget, cells: *const u8, cell: usize) {
+    // SAFETY: `tape` is the length of the tape of the run
+    let cells = unsafe { std::slice::from_raw_parts(cells, budget.tape) };
+    dump_tape(cells, cell);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        cell::RefCell,
+        io::{ErrorKind, Write},
+        process::{Command, Stdio},
+        rc::Rc,
+    };
+
+    use crate::{compile, Options, Printer, Runner, Scanner};
+
+    /// Hello world which multiplies one input byte by 5 and prints it, uses every op but `#`
+    pub(crate) const HELLO_INPUT: &[u8] =
+        b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.\
+        +++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.,[>+++++<-]>.[-]";
+
+    /// Output of `code` run by `T`, `,` reads 12
+    pub(crate) fn output<T: Runner>(code: &[u8]) -> Vec<u8> {
+        let mut ops = compile::compile(code);
+
+        let print_buffer = Rc::new(RefCell::new(Vec::new()));
+        let buffer = print_buffer.clone();
+        let mut printer = Printer::new(move |value| buffer.borrow_mut().push(value));
+        let mut scanner = Scanner::new(|| 12);
+        let mut cells = vec![0u8; 30000];
+
+        T::exec(
+            &mut ops,
+            &mut cells,
+            &mut printer,
+            &mut scanner,
+            &Options::default(),
+        )
+        .unwrap();
+        print_buffer.take()
+    }
+
+    /// Output of the program which `compiler` builds from `source`, run with the byte `,` of
+    /// [`output`] reads on stdin. The compiler gets the source file with `extension`, `args` and
+    /// `-o <executable>`. Returns none if the compiler is not installed.
+    pub(crate) fn native_output(
+        compiler: &str,
+        args: &[&str],
+        source: &[u8],
+        extension: &str,
+    ) -> Option<Vec<u8>> {
+        let dir =
+            std::env::temp_dir().join(format!("bfjit-{compiler}-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("program.{extension}"));
+        let executable = dir.join("program");
+        std::fs::write(&path, source).unwrap();
+
+        let status = Command::new(compiler)
+            .arg(&path)
+            .args(args)
+            .arg("-o")
+            .arg(&executable)
+            .status();
+        let status = match status {
+            Ok(status) => status,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                std::fs::remove_dir_all(&dir).unwrap();
+                return None;
+            }
+            Err(e) => panic!("could not run {compiler}: {e}"),
+        };
+        assert!(status.success(), "{compiler} failed with {status}");
+
+        let mut program = Command::new(&executable)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        program.stdin.take().unwrap().write_all(&[12]).unwrap();
+        let output = program.wait_with_output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            output.status.success(),
+            "the program failed with {}",
+            output.status
+        );
+        Some(output.stdout)
+    }
+}
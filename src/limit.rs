@@ -0,0 +1,249 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{RunError, State};
+
+/// Number of executed `]` between two checks of the deadline and the cancellation token
+const CHECK_INTERVAL: u64 = 1 << 16;
+
+/// Limits of a single run, a step is one executed `]`
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Maximum number of steps
+    pub steps: Option<u64>,
+    /// Maximum wall-clock time
+    pub timeout: Option<Duration>,
+    /// Stops the run once it is cancelled
+    pub cancel: Option<CancellationToken>,
+    /// Maximum number of cells a growing tape grows to
+    pub cells: Option<usize>,
+}
+
+impl Limits {
+    /// Returns if there is no limit which needs the steps to be counted, the tape limit is only
+    /// checked when the tape grows
+    pub fn is_unlimited(&self) -> bool {
+        self.steps.is_none() && self.timeout.is_none() && self.cancel.is_none()
+    }
+}
+
+/// Cancels runs from another thread, clones share the same flag
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Checks the [`Limits`] of one run, the runners count down the steps between two checks
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    /// steps of all refills, the current one included
+    steps: u64,
+    max_steps: Option<u64>,
+    deadline: Option<Instant>,
+    cancel: Option<CancellationToken>,
+    max_cells: usize,
+    stopped: Option<fn(State) -> RunError>,
+}
+
+impl Limiter {
+    /// Starts the clock of the `limits`
+    pub(crate) fn new(limits: &Limits) -> Self {
+        Self {
+            steps: 0,
+            max_steps: limits.steps,
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            cancel: limits.cancel.clone(),
+            max_cells: limits.cells.unwrap_or(usize::MAX),
+            stopped: None,
+        }
+    }
+
+    /// Checks the limits once the steps of the last refill are used up, returns the steps until
+    /// the next check or none if the run has to stop
+    pub(crate) fn check(&mut self) -> Option<u64> {
+        if self.max_steps.is_some_and(|max| self.steps > max) {
+            self.stopped = Some(RunError::StepLimit);
+        } else if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            self.stopped = Some(RunError::Timeout);
+        } else if self
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.is_cancelled())
+        {
+            self.stopped = Some(RunError::Cancelled);
+        }
+        match self.stopped {
+            Some(_) => None,
+            None => Some(self.refill()),
+        }
+    }
+
+    /// Returns the steps until the next check
+    pub(crate) fn refill(&mut self) -> u64 {
+        // the step after the last allowed one has to reach `check`
+        let left = self
+            .max_steps
+            .map_or(u64::MAX, |max| (max - self.steps).saturating_add(1));
+        let counter = if self.deadline.is_some() || self.cancel.is_some() {
+            left.min(CHECK_INTERVAL)
+        } else {
+            left
+        };
+        self.steps += counter;
+        counter
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped.is_some()
+    }
+
+    /// Maximum number of cells of the tape
+    pub(crate) fn max_cells(&self) -> usize {
+        self.max_cells
+    }
+
+    /// Stops the run because the tape would grow beyond [`Limits::cells`]
+    pub(crate) fn stop_tape(&mut self) {
+        self.stopped = Some(RunError::TapeLimit);
+    }
+
+    /// State of the run at the op with index `op` with `counter` steps left until the next check,
+    /// `cell` is the current cell index
+    pub(crate) fn state(&self, counter: u64, cell: usize, op: usize) -> State {
+        State {
+            cell,
+            op,
+            steps: self.steps - counter,
+        }
+    }
+
+    /// Returns the error if the run was stopped at the op with index `op`, like
+    /// [`Limiter::state`]
+    pub(crate) fn result(&self, counter: u64, cell: usize, op: usize) -> Result<(), RunError> {
+        match self.stopped {
+            Some(error) => Err(error(self.state(counter, cell, op))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{CancellationToken, Limiter, Limits};
+    use crate::{
+        cljit::ClJit, compile, interpret::Interpreter, jit::Jit, threaded::Threaded,
+        tiered::Tiered, Options, Printer, RunError, Runner, Scanner, State,
+    };
+
+    fn run<T: Runner>(code: &[u8], limits: Limits) -> Result<(), RunError> {
+        let mut ops = compile::compile(code);
+        let mut printer = Printer::new(|_| {});
+        let mut scanner = Scanner::new(|| 0);
+        let mut cells = vec![0u8; 30000];
+        let options = Options {
+            limits,
+            ..Options::default()
+        };
+        T::exec(&mut ops, &mut cells, &mut printer, &mut scanner, &options)
+    }
+
+    #[test]
+    fn limiter_steps() {
+        let mut limiter = Limiter::new(&Limits {
+            steps: Some(3),
+            ..Limits::default()
+        });
+
+        // the counter of the runners reaches zero after the 4th step
+        assert_eq!(limiter.refill(), 4);
+        assert_eq!(limiter.state(1, 0, 1).steps, 3);
+        assert!(limiter.result(1, 0, 1).is_ok());
+        assert_eq!(limiter.check(), None);
+        assert_eq!(
+            limiter.result(1, 7, 1),
+            Err(RunError::StepLimit(State {
+                cell: 7,
+                op: 1,
+                steps: 3
+            }))
+        );
+    }
+
+    #[test]
+    fn limits_stop_runners() {
+        let steps = Limits {
+            steps: Some(5000),
+            ..Limits::default()
+        };
+        let stopped = Err(RunError::StepLimit(State {
+            cell: 5001,
+            op: 4,
+            steps: 5000,
+        }));
+        assert_eq!(run::<Interpreter>(b"+[>+]", steps.clone()), stopped);
+        assert_eq!(run::<Threaded>(b"+[>+]", steps.clone()), stopped);
+        assert_eq!(run::<Tiered>(b"+[>+]", steps.clone()), stopped);
+        assert_eq!(run::<Jit>(b"+[>+]", steps.clone()), stopped);
+        assert_eq!(run::<ClJit>(b"+[>+]", steps.clone()), stopped);
+
+        fn timed_out<T: Runner>() -> bool {
+            let timeout = Limits {
+                timeout: Some(Duration::from_millis(10)),
+                ..Limits::default()
+            };
+            matches!(run::<T>(b"+[]", timeout), Err(RunError::Timeout(_)))
+        }
+        assert!(timed_out::<Interpreter>());
+        assert!(timed_out::<Threaded>());
+        assert!(timed_out::<Tiered>());
+        assert!(timed_out::<Jit>());
+        assert!(timed_out::<ClJit>());
+    }
+
+    #[test]
+    fn cancel_runners() {
+        fn cancelled<T: Runner>() -> bool {
+            let cancel = CancellationToken::new();
+            let token = cancel.clone();
+            let canceller = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                token.cancel();
+            });
+            let limits = Limits {
+                cancel: Some(cancel),
+                ..Limits::default()
+            };
+            let result = run::<T>(b"+[]", limits);
+            canceller.join().unwrap();
+            matches!(result, Err(RunError::Cancelled(_)))
+        }
+
+        assert!(cancelled::<Interpreter>());
+        assert!(cancelled::<Threaded>());
+        assert!(cancelled::<Tiered>());
+        assert!(cancelled::<Jit>());
+        assert!(cancelled::<ClJit>());
+    }
+}
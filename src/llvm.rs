@@ -0,0 +1,185 @@
+use std::fmt::Write;
+
+use crate::compile::OpCode;
+
+/// Translates `ops` into textual LLVM IR with a `main` function, the tape of `cells` cells is the
+/// global `@tape`, `@putchar` and `@getchar` are used for in- and output, debug ops are skipped
+pub fn translate(ops: &[OpCode], cells: usize) -> String {
+    let mut t = Translator {
+        out: String::new(),
+        next_value: 0,
+        next_loop: 0,
+        loops: Vec::new(),
+    };
+
+    writeln!(
+        t.out,
+        "@tape = internal global [{cells} x i8] zeroinitializer"
+    )
+    .unwrap();
+    writeln!(t.out).unwrap();
+    writeln!(t.out, "declare i32 @putchar(i32)").unwrap();
+    writeln!(t.out, "declare i32 @getchar()").unwrap();
+    writeln!(t.out).unwrap();
+    writeln!(t.out, "define i32 @main() {{").unwrap();
+    writeln!(t.out, "entry:").unwrap();
+    writeln!(t.out, "  %p = alloca i64").unwrap();
+    writeln!(t.out, "  store i64 0, ptr %p").unwrap();
+
+    for op in ops {
+        t.translate(*op);
+    }
+
+    writeln!(t.out, "  ret i32 0").unwrap();
+    writeln!(t.out, "}}").unwrap();
+    t.out
+}
+
+struct Translator {
+    out: String,
+    next_value: usize,
+    next_loop: usize,
+    loops: Vec<usize>,
+}
+
+impl Translator {
+    fn translate(&mut self, op: OpCode) {
+        match op {
+            OpCode::Right { count } => self.move_pointer(count as i64),
+            OpCode::Left { count } => self.move_pointer(-(count as i64)),
+            OpCode::Inc { count, offset } => self.change_cell(offset, "add", count),
+            OpCode::Dec { count, offset } => self.change_cell(offset, "sub", count),
+            OpCode::Output => {
+                let (_, value) = self.load_cell(0);
+                let wide = self.value();
+                self.line(format!("{wide} = zext i8 {value} to i32"));
+                let ret = self.value();
+                self.line(format!("{ret} = call i32 @putchar(i32 {wide})"));
+            }
+            OpCode::Input => {
+                let read = self.value();
+                self.line(format!("{read} = call i32 @getchar()"));
+                let eof = self.value();
+                self.line(format!("{eof} = icmp slt i32 {read}, 0"));
+                let wide = self.value();
+                self.line(format!("{wide} = select i1 {eof}, i32 0, i32 {read}"));
+                let value = self.value();
+                self.line(format!("{value} = trunc i32 {wide} to i8"));
+                let cell = self.cell(0);
+                self.line(format!("store i8 {value}, ptr {cell}"));
+            }
+            OpCode::JumpIfZero { .. } => {
+                let id = self.next_loop;
+                self.next_loop += 1;
+                self.loops.push(id);
+
+                self.line(format!("br label %loop{id}"));
+                writeln!(self.out, "loop{id}:").unwrap();
+                let (_, value) = self.load_cell(0);
+                let zero = self.value();
+                self.line(format!("{zero} = icmp eq i8 {value}, 0"));
+                self.line(format!(
+                    "br i1 {zero}, label %loop{id}.end, label %loop{id}.body"
+                ));
+                writeln!(self.out, "loop{id}.body:").unwrap();
+            }
+            OpCode::JumpIfNotZero { .. } => {
+                let id = self.loops.pop().expect("Closing ] without [");
+                self.line(format!("br label %loop{id}"));
+                writeln!(self.out, "loop{id}.end:").unwrap();
+            }
+            OpCode::SetZero => {
+                let cell = self.cell(0);
+                self.line(format!("store i8 0, ptr {cell}"));
+            }
+            OpCode::Mul { factor, offset } => {
+                let (cell, value) = self.load_cell(0);
+                let product = self.value();
+                self.line(format!("{product} = mul i8 {value}, {}", factor as i8));
+                let (dest_cell, dest) = self.load_cell(offset);
+                let sum = self.value();
+                self.line(format!("{sum} = add i8 {dest}, {product}"));
+                self.line(format!("store i8 {sum}, ptr {dest_cell}"));
+                self.line(format!("store i8 0, ptr {cell}"));
+            }
+            OpCode::Debug => {}
+        }
+    }
+
+    fn move_pointer(&mut self, count: i64) {
+        let index = self.value();
+        self.line(format!("{index} = load i64, ptr %p"));
+        let moved = self.value();
+        self.line(format!("{moved} = add i64 {index}, {count}"));
+        self.line(format!("store i64 {moved}, ptr %p"));
+    }
+
+    fn change_cell(&mut self, offset: i32, instruction: &str, count: u8) {
+        let (cell, value) = self.load_cell(offset);
+        let changed = self.value();
+        // i8 constants are signed in LLVM IR
+        let count = count as i8;
+        self.line(format!("{changed} = {instruction} i8 {value}, {count}"));
+        self.line(format!("store i8 {changed}, ptr {cell}"));
+    }
+
+    /// returns the pointer to the current cell + `offset`
+    fn cell(&mut self, offset: i32) -> String {
+        let index = self.value();
+        self.line(format!("{index} = load i64, ptr %p"));
+        let index = if offset == 0 {
+            index
+        } else {
+            let with_offset = self.value();
+            self.line(format!("{with_offset} = add i64 {index}, {offset}"));
+            with_offset
+        };
+        let cell = self.value();
+        self.line(format!(
+            "{cell} = getelementptr inbounds i8, ptr @tape, i64 {index}"
+        ));
+        cell
+    }
+
+    /// returns (pointer, value) of the current cell + `offset`
+    fn load_cell(&mut self, offset: i32) -> (String, String) {
+        let cell = self.cell(offset);
+        let value = self.value();
+        self.line(format!("{value} = load i8, ptr {cell}"));
+        (cell, value)
+    }
+
+    fn value(&mut self) -> String {
+        self.next_value += 1;
+        format!("%v{}", self.next_value)
+    }
+
+    fn line(&mut self, line: String) {
+        writeln!(self.out, "  {line}").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compile,
+        interpret::Interpreter,
+        tests::{native_output, output, HELLO_INPUT},
+    };
+
+    #[test]
+    fn code_llvm() {
+        let ops = compile::compile(b"+[-]>,[.,]");
+        let ir = super::translate(&ops, 30000);
+
+        assert!(ir.starts_with("@tape = internal global [30000 x i8] zeroinitializer\n"));
+        assert!(ir.contains("\nloop0:\n"));
+        assert!(ir.contains("\nloop0.end:\n"));
+        assert!(ir.trim_end().ends_with("  ret i32 0\n}"));
+
+        let ir = super::translate(&compile::compile(HELLO_INPUT), 30000);
+        if let Some(native) = native_output("clang", &[], ir.as_bytes(), "ll") {
+            assert_eq!(native, output::<Interpreter>(HELLO_INPUT));
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::VecDeque;
+
+use anyhow::Context;
+
+use crate::{
+    compile::{self, OpCode, Reader},
+    dump_tape,
+    interpret::back_patch,
+    printer_function, Printer,
+};
+
+pub const MAGIC: &[u8; 4] = b"BFM\0";
+/// Version of the state format written by [`Machine::save`]
+pub const VERSION: u16 = 1;
+
+/// Interpreter which runs one op at a time and can be paused, saved and resumed.
+///
+/// Input is fed into the machine instead of being read by a [`crate::Scanner`], a `,` without
+/// input pauses the machine until more input is fed or the input is closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Machine {
+    ops: Vec<OpCode>,
+    cells: Vec<u8>,
+    cell: usize,
+    ip: usize,
+    input: VecDeque<u8>,
+    /// `,` reads 0 once the input is closed and empty
+    input_closed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The next op can be executed
+    Ready,
+    /// The next op is `,` and there is no input, see [`Machine::feed`]
+    NeedsInput,
+    /// The program ended
+    Finished,
+}
+
+impl Machine {
+    pub fn new(mut ops: Vec<OpCode>, cells: usize) -> Self {
+        back_patch(&mut ops);
+        Self {
+            ops,
+            cells: vec![0; cells],
+            cell: 0,
+            ip: 0,
+            input: VecDeque::new(),
+            input_closed: false,
+        }
+    }
+
+    pub fn ops(&self) -> &[OpCode] {
+        &self.ops
+    }
+
+    pub fn cells(&self) -> &[u8] {
+        &self.cells
+    }
+
+    /// Index of the current cell
+    pub fn cell(&self) -> usize {
+        self.cell
+    }
+
+    /// Index of the next op
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Appends `input` to the input of the program
+    pub fn feed(&mut self, input: &[u8]) {
+        self.input.extend(input);
+    }
+
+    /// Marks the end of the input, reading after it results in 0
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    pub fn status(&self) -> Status {
+        match self.ops.get(self.ip) {
+            None => Status::Finished,
+            Some(OpCode::Input) if self.input.is_empty() && !self.input_closed => {
+                Status::NeedsInput
+            }
+            Some(_) => Status::Ready,
+        }
+    }
+
+    /// Executes the next op if the machine is [`Status::Ready`], returns the new status. Fails
+    /// without executing the op if it uses a cell out of the tape or moves the pointer left of it.
+    pub fn step(&mut self, printer: &mut Printer) -> anyhow::Result<Status> {
+        if self.status() != Status::Ready {
+            return Ok(self.status());
+        }
+
+        match self.ops[self.ip] {
+            OpCode::Right { count } => self.cell = self.cell.saturating_add(count as usize),
+            OpCode::Left { count } => {
+                self.cell = self
+                    .cell
+                    .checked_sub(count as usize)
+                    .context("the pointer moved left of the tape")?
+            }
+            OpCode::Inc { count, offset } => {
+                let cell = self.index(offset)?;
+                self.cells[cell] = self.cells[cell].wrapping_add(count);
+            }
+            OpCode::Dec { count, offset } => {
+                let cell = self.index(offset)?;
+                self.cells[cell] = self.cells[cell].wrapping_sub(count);
+            }
+            OpCode::Output => printer_function(printer, self.cells[self.index(0)?]),
+            OpCode::Input => {
+                let cell = self.index(0)?;
+                self.cells[cell] = self.input.pop_front().unwrap_or(0);
+            }
+            OpCode::JumpIfZero { target } => {
+                if self.cells[self.index(0)?] == 0 {
+                    self.ip = target;
+                    return Ok(self.status());
+                }
+            }
+            OpCode::JumpIfNotZero { target } => {
+                if self.cells[self.index(0)?] != 0 {
+                    self.ip = target;
+                    return Ok(self.status());
+                }
+            }
+            OpCode::SetZero => {
+                let cell = self.index(0)?;
+                self.cells[cell] = 0;
+            }
+            OpCode::Mul { factor, offset } => {
+                let cell = self.index(0)?;
+                let off_cell = self.index(offset)?;
+
+                self.cells[off_cell] =
+                    self.cells[off_cell].wrapping_add(self.cells[cell].wrapping_mul(factor));
+                self.cells[cell] = 0;
+            }
+            OpCode::Debug => dump_tape(&self.cells, self.cell),
+        }
+        self.ip += 1;
+        Ok(self.status())
+    }
+
+    /// Index of the cell `offset` cells right of the pointer, fails if it is out of the tape
+    fn index(&self, offset: i32) -> anyhow::Result<usize> {
+        self.cell
+            .checked_add_signed(offset as isize)
+            .filter(|cell| *cell < self.cells.len())
+            .with_context(|| {
+                format!(
+                    "cell {} is out of the tape of {} cells",
+                    self.cell as isize + offset as isize,
+                    self.cells.len()
+                )
+            })
+    }
+
+    /// Steps until `predicate` holds before the next op or the machine is not
+    /// [`Status::Ready`] anymore, fails like [`Machine::step`]
+    pub fn run_until(
+        &mut self,
+        printer: &mut Printer,
+        mut predicate: impl FnMut(&Machine) -> bool,
+    ) -> anyhow::Result<Status> {
+        let mut status = self.status();
+        while status == Status::Ready && !predicate(self) {
+            status = self.step(printer)?;
+        }
+        Ok(status)
+    }
+
+    /// Runs until the program ends or needs input
+    pub fn resume(&mut self, printer: &mut Printer) -> anyhow::Result<Status> {
+        self.run_until(printer, |_| false)
+    }
+
+    /// Serializes the complete state, the ops are stored as bytecode
+    pub fn save(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(MAGIC);
+        out.extend(VERSION.to_le_bytes());
+        out.extend((self.ip as u64).to_le_bytes());
+        out.extend((self.cell as u64).to_le_bytes());
+        out.push(self.input_closed as u8);
+        out.extend((self.input.len() as u64).to_le_bytes());
+        out.extend(&self.input);
+        out.extend((self.cells.len() as u64).to_le_bytes());
+        out.extend(&self.cells);
+        out.extend(compile::save(&self.ops));
+        out
+    }
+
+    /// Deserializes a state written by [`Machine::save`], the pointer can be right of the tape like
+    /// after [`Machine::step`], only the ops using its cell fail
+    pub fn load(state: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader::new(state);
+
+        if reader.bytes::<4>()? != *MAGIC {
+            anyhow::bail!("not a machine state");
+        }
+        let version = u16::from_le_bytes(reader.bytes()?);
+        if version != VERSION {
+            anyhow::bail!("unsupported machine state version {version}, expected {VERSION}");
+        }
+        let ip = reader.u64()? as usize;
+        let cell = reader.u64()? as usize;
+        let input_closed = reader.u8()? != 0;
+        let len = reader.u64()? as usize;
+        let input = reader.slice(len)?.iter().copied().collect();
+        let len = reader.u64()? as usize;
+        let cells = reader.slice(len)?.to_vec();
+        let mut ops = compile::load(reader.rest())?;
+        back_patch(&mut ops);
+
+        if ip > ops.len() {
+            anyhow::bail!("instruction pointer {ip} is out of the program");
+        }
+        Ok(Self {
+            ops,
+            cells,
+            cell,
+            ip,
+            input,
+            input_closed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{Machine, Status};
+    use crate::{compile, Printer};
+
+    #[test]
+    fn machine_resume() {
+        let print_buffer = Rc::new(RefCell::new(Vec::new()));
+        let buffer = print_buffer.clone();
+        let mut printer = Printer::new(move |value| buffer.borrow_mut().push(value));
+
+        let mut machine = Machine::new(compile::compile(b",[.,]"), 16);
+        assert_eq!(machine.resume(&mut printer).unwrap(), Status::NeedsInput);
+        machine.feed(b"ab");
+        assert_eq!(machine.resume(&mut printer).unwrap(), Status::NeedsInput);
+        assert_eq!(print_buffer.take(), b"ab");
+
+        let mut machine = Machine::load(&machine.save()).unwrap();
+        machine.feed(b"c");
+        machine.close_input();
+        assert_eq!(machine.resume(&mut printer).unwrap(), Status::Finished);
+        assert_eq!(print_buffer.take(), b"c");
+
+        // the failed op is not executed
+        let mut machine = Machine::new(compile::compile(b"+<+"), 16);
+        let error = machine.resume(&mut printer).unwrap_err();
+        assert_eq!(error.to_string(), "the pointer moved left of the tape");
+        assert_eq!((machine.ip(), machine.cells()[0]), (1, 1));
+
+        // a pointer right of the tape is saved and loaded, only the ops using its cell fail
+        let (ops, _) = compile::compile_unoptimized(b">>>><+.", Default::default());
+        let mut machine = Machine::new(ops, 4);
+        machine
+            .run_until(&mut printer, |machine| machine.cell() == 4)
+            .unwrap();
+        let mut machine = Machine::load(&machine.save()).unwrap();
+        assert_eq!(machine.cell(), 4);
+        assert_eq!(machine.resume(&mut printer).unwrap(), Status::Finished);
+        assert_eq!(print_buffer.take(), [1]);
+    }
+}
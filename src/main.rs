@@ -1,77 +1,470 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    fs::File,
+    io::{stdin, stdout, BufWriter, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use bfjit::cljit::ClJit;
+use anyhow::Context;
+use bfjit::cljit::{self, ClJit};
+use bfjit::coverage::{self, Coverage};
+use bfjit::debug::Debugger;
 use bfjit::interpret::Interpreter;
 use bfjit::jit::Jit;
-use bfjit::{compile, make_printer, make_scanner, run};
-use bfjit::{meassure::Measured, Runner};
-use clap::{Parser, ValueEnum};
+use bfjit::meassure::{self, Measured};
+use bfjit::profile::{self, Profiler};
+use bfjit::threaded::Threaded;
+use bfjit::tiered::Tiered;
+use bfjit::trace::Tracer;
+use bfjit::{aot, c, cache, compile, elf, llvm, make_printer, make_scanner, run, rust, wasm};
+use bfjit::{compile::OpCode, limit::Limits, source::Source, Options, Runner};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Debug, Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(value_enum, long, short, default_value_t = RunKind::Interpret)]
     run: RunKind,
-    #[arg(long, short, default_value_t = 30_000)]
+    #[arg(long, short, global = true, default_value_t = 30_000)]
     cells: usize,
     #[arg(long, short, num_args = 0..=1, default_missing_value = "10")]
     meassure: Option<usize>,
-    path: PathBuf,
+    /// Runs left out of the statistics of `--meassure` and `--compare`
+    #[arg(long, default_value_t = 0)]
+    warmup: usize,
+    /// Measures the program with every runner and compares their speed to the interpreter,
+    /// `--meassure` sets the number of runs. The profile, trace and coverage would be summed over
+    /// all runners, so they can not be combined with it.
+    #[arg(long, conflicts_with_all = ["emit", "profile", "trace", "coverage"])]
+    compare: bool,
+    /// Prints the generated code of the selected backend instead of running the program
+    #[arg(value_enum, long)]
+    emit: Option<Emit>,
+    /// Writes the output of `--emit` to this file instead of stdout
+    #[arg(long, short, requires = "emit")]
+    output: Option<PathBuf>,
+    /// Cranelift optimization level
+    #[arg(value_enum, long, global = true, default_value_t = cljit::OptLevel::None)]
+    cl_opt_level: cljit::OptLevel,
+    /// Runs the Cranelift IR verifier during compilation
+    #[arg(long, global = true)]
+    cl_verify: bool,
+    /// Sets an additional Cranelift flag, e.g. `--cl-flag regalloc_checker=true`
+    #[arg(long, global = true, value_parser = parse_flag)]
+    cl_flag: Vec<(String, String)>,
+    /// Caches the optimized program and the code of `jit` between runs
+    #[arg(long)]
+    cache: bool,
+    /// Directory of the cache, implies `--cache`
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+    /// Stops the program after this many executed `]`
+    #[arg(long)]
+    max_steps: Option<u64>,
+    /// Stops the program after this many seconds
+    #[arg(long, value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+    /// `#` prints the pointer and the tape around it to stderr
+    #[arg(long, global = true)]
+    debug_ops: bool,
+    /// The input of the program follows after the first `!` instead of being read from stdin
+    #[arg(long, global = true)]
+    bundled_input: bool,
+    /// Prints how often every loop was entered and iterated to stderr
+    #[arg(long)]
+    profile: bool,
+    /// Starts the pointer in the middle of the tape, so programs can move left of their first
+    /// cell
+    #[arg(long)]
+    bidirectional: bool,
+    /// Grows the tape when the program uses a cell right of its end, `--cells` is the initial size
+    #[arg(long)]
+    grow_tape: bool,
+    /// Stops the program when the tape would grow beyond this many cells
+    #[arg(long, default_value_t = 1 << 30)]
+    max_cells: usize,
+    /// Prints which cells were touched and how far the pointer moved to stderr
+    #[arg(long)]
+    coverage: bool,
+    /// Writes every executed op with the pointer and the current cell to this file
+    #[arg(long)]
+    trace: Option<PathBuf>,
+    /// Only traces the ops in these source lines, e.g. `--trace-lines 3-5`
+    #[arg(long, requires = "trace", value_parser = parse_lines)]
+    trace_lines: Option<(usize, usize)>,
+    /// Only writes the last ops traced before the program is stopped by its limits or panics
+    #[arg(long, requires = "trace")]
+    trace_last: Option<usize>,
+    #[arg(required = true)]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compiles the program into a native executable, `crane-lift` links an object file,
+    /// `jit` writes a static executable directly
+    Build {
+        #[arg(value_enum, long, short, default_value_t = RunKind::CraneLift)]
+        run: RunKind,
+        path: PathBuf,
+        #[arg(long, short)]
+        output: PathBuf,
+        /// Program used to link the object file, it gets called with `<object> -o <output>`
+        #[arg(long, default_value = "cc")]
+        linker: String,
+    },
+    /// Debugs the program interactively, `h` lists the commands
+    Debug { path: PathBuf },
 }
 
 #[derive(Debug, ValueEnum, Clone, Copy)]
 enum RunKind {
     Interpret,
+    /// Interpreter with closure compiled ops, for machines without executable memory
+    Threaded,
+    /// Interpreter which compiles hot loops with cranelift
+    Tiered,
     Jit,
     CraneLift,
 }
 
+#[derive(Debug, ValueEnum, Clone, Copy)]
+enum Emit {
+    /// Annotated disassembly of the machine code generated by `jit` or `crane-lift`
+    Asm,
+    /// Cranelift IR of the program
+    Clif,
+    /// C source of the program
+    C,
+    /// WebAssembly module
+    Wasm,
+    /// WebAssembly module in the text format
+    Wat,
+    /// Textual LLVM IR
+    Llvm,
+    /// Rust source of the program
+    Rust,
+    /// Optimized ops in the bytecode format, which can be run instead of the source
+    Bytecode,
+}
+
+fn parse_flag(flag: &str) -> Result<(String, String), String> {
+    let Some((name, value)) = flag.split_once('=') else {
+        return Err(format!("expected `name=value`, got `{flag}`"));
+    };
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    seconds
+        .parse()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("expected a number of seconds, got `{seconds}`"))
+}
+
+fn parse_lines(lines: &str) -> Result<(usize, usize), String> {
+    let parse = |line: &str| line.trim().parse::<usize>().ok().filter(|line| *line > 0);
+    let (first, last) = match lines.split_once('-') {
+        Some((first, last)) => (parse(first), parse(last)),
+        None => (parse(lines), parse(lines)),
+    };
+    match (first, last) {
+        (Some(first), Some(last)) if first <= last => Ok((first, last)),
+        _ => Err(format!("expected lines like `3-5`, got `{lines}`")),
+    }
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    let code = std::fs::read(args.path)?;
+    let mut options = Options {
+        cranelift: cljit::Settings {
+            opt_level: args.cl_opt_level,
+            verify: args.cl_verify,
+            flags: args.cl_flag,
+        },
+        cache: None,
+        limits: Limits {
+            steps: args.max_steps,
+            timeout: args.timeout,
+            cancel: None,
+            cells: Some(args.max_cells),
+        },
+        extensions: compile::Extensions {
+            debug_ops: args.debug_ops,
+            bundled_input: args.bundled_input,
+        },
+        profile: args.profile.then(Profiler::new),
+        trace: None,
+        coverage: args.coverage.then(Coverage::new),
+        start_cell: if args.bidirectional {
+            args.cells / 2
+        } else {
+            0
+        },
+        grow_tape: args.grow_tape,
+    };
+    // report invalid flags before running anything
+    options.cranelift.flags()?;
 
-    if let Some(measure_count) = args.meassure {
-        let measurements = match args.run {
-            RunKind::Interpret => run_meassured::<Interpreter>,
-            RunKind::Jit => run_meassured::<Jit>,
-            RunKind::CraneLift => run_meassured::<ClJit>,
-        }(&code, args.cells, measure_count);
+    match args.command {
+        Some(Command::Build {
+            run,
+            path,
+            output,
+            linker,
+        }) => {
+            let ops = compile::compile_or_load(&std::fs::read(path)?, options.extensions)?;
+            return match run {
+                RunKind::Interpret | RunKind::Threaded | RunKind::Tiered => {
+                    anyhow::bail!("the interpreters can not build executables")
+                }
+                RunKind::Jit => write_executable(&output, &elf::elf(&ops, args.cells)),
+                RunKind::CraneLift => {
+                    aot::build(&ops, args.cells, &options.cranelift, &output, &linker)
+                }
+            };
+        }
+        Some(Command::Debug { path }) => {
+            let code = std::fs::read(path)?;
+            if compile::is_bytecode(&code) {
+                anyhow::bail!("bytecode has no source to debug");
+            }
+            let mut printer = make_printer();
+            return Debugger::new(&code, args.cells, options.extensions).repl(
+                stdin().lock(),
+                stdout(),
+                &mut printer,
+            );
+        }
+        None => {}
+    }
+
+    let code = std::fs::read(args.path.expect("path is required without a subcommand"))?;
+
+    let cache_dir = (args.cache || args.cache_dir.is_some())
+        .then(|| args.cache_dir.unwrap_or_else(cache::default_dir));
+    options.cache = cache_entry(cache_dir.as_deref(), &code, args.run, &options);
+
+    if let Some(path) = &args.trace {
+        let mut tracer = Tracer::new(BufWriter::new(File::create(path)?));
+        if let Some((first, last)) = args.trace_lines {
+            let Some(source) = compile_with_source(&code, options.extensions)?.1 else {
+                anyhow::bail!("bytecode has no lines to trace");
+            };
+            tracer = tracer.filter(source.ops_in_lines(first, last));
+        }
+        if let Some(len) = args.trace_last {
+            tracer = tracer.ring(len);
+        }
+        options.trace = Some(tracer);
+    }
+
+    if let Some(emit) = args.emit {
+        let (ops, source) = compile_with_source(&code, options.extensions)?;
+        let out = match emit {
+            Emit::Asm => match args.run {
+                RunKind::Interpret | RunKind::Threaded | RunKind::Tiered => {
+                    anyhow::bail!("the interpreters generate no machine code")
+                }
+                RunKind::Jit => Jit::disassemble(&ops, source.as_ref()),
+                RunKind::CraneLift => {
+                    ClJit::disassemble(&ops, &options.cranelift, source.as_ref())?
+                }
+            }
+            .into_bytes(),
+            Emit::Clif => ClJit::clif(&ops, &options.cranelift)?.into_bytes(),
+            Emit::C => c::translate(&ops, args.cells).into_bytes(),
+            Emit::Wasm => wasm::translate(&ops, args.cells),
+            Emit::Wat => wasm::translate_wat(&ops, args.cells).into_bytes(),
+            Emit::Llvm => llvm::translate(&ops, args.cells).into_bytes(),
+            Emit::Rust => rust::translate(&ops, args.cells).into_bytes(),
+            Emit::Bytecode => compile::save(&ops),
+        };
+        match args.output {
+            Some(path) => std::fs::write(path, out)?,
+            None => stdout().write_all(&out)?,
+        }
+        return Ok(());
+    }
+
+    if args.compare {
+        let measure_count = args.meassure.unwrap_or(10);
+        let mut rows = Vec::new();
+        for &kind in RunKind::value_variants() {
+            let options = Options {
+                cache: cache_entry(cache_dir.as_deref(), &code, kind, &options),
+                ..options.clone()
+            };
+            let measurements = run_kind_meassured(kind, &code, args.cells, measure_count, &options)
+                .with_context(|| format!("measuring {}", kind_name(kind)))?;
+            rows.push((kind_name(kind), run_stats(&measurements, args.warmup)?));
+        }
+
+        // the interpreter is the first runner
+        print!("{}", meassure::table(&rows, Some(rows[0].1.median)));
+    } else if let Some(measure_count) = args.meassure {
+        let measurements = run_kind_meassured(args.run, &code, args.cells, measure_count, &options);
+        print_profile(&code, &options)?;
+        print_coverage(&options);
+        let measurements = measurements?;
 
         for (name, duration) in &measurements.measurements {
             println!("{name}: {duration:?}");
         }
-        println!(
-            "time: {:?}",
-            measurements
-                .measurements
-                .into_iter()
-                .map(|(_, d)| d)
-                .sum::<Duration>()
-        );
+        let stats = run_stats(&measurements, args.warmup)?;
+        print!("{}", meassure::table(&[(kind_name(args.run), stats)], None));
+        println!("time: {:?}", measurements.total());
     } else {
-        match args.run {
-            RunKind::Interpret => run::<Interpreter>(&code, args.cells),
-            RunKind::Jit => run::<Jit>(&code, args.cells),
-            RunKind::CraneLift => run::<ClJit>(&code, args.cells),
+        let result = match args.run {
+            RunKind::Interpret => run::<Interpreter>(&code, args.cells, &options),
+            RunKind::Threaded => run::<Threaded>(&code, args.cells, &options),
+            RunKind::Tiered => run::<Tiered>(&code, args.cells, &options),
+            RunKind::Jit => run::<Jit>(&code, args.cells, &options),
+            RunKind::CraneLift => run::<ClJit>(&code, args.cells, &options),
         };
+        print_profile(&code, &options)?;
+        print_coverage(&options);
+        result?;
+    }
+
+    Ok(())
+}
+
+/// Cache entry of `code` for the runner `kind`, if there is a cache `dir`
+fn cache_entry(
+    dir: Option<&Path>,
+    code: &[u8],
+    kind: RunKind,
+    options: &Options,
+) -> Option<cache::Entry> {
+    let backend = format!("{kind:?}");
+    dir.map(|dir| cache::Entry::new(dir, code, &backend, options.extensions, &options.cranelift))
+}
+
+/// Name of `kind` on the command line
+fn kind_name(kind: RunKind) -> String {
+    kind.to_possible_value()
+        .expect("no runner is skipped")
+        .get_name()
+        .to_string()
+}
+
+fn run_kind_meassured(
+    kind: RunKind,
+    code: &[u8],
+    cells: usize,
+    meassure: usize,
+    options: &Options,
+) -> anyhow::Result<Measured<()>> {
+    let run = match kind {
+        RunKind::Interpret => run_meassured::<Interpreter>,
+        RunKind::Threaded => run_meassured::<Threaded>,
+        RunKind::Tiered => run_meassured::<Tiered>,
+        RunKind::Jit => run_meassured::<Jit>,
+        RunKind::CraneLift => run_meassured::<ClJit>,
+    };
+    run(code, cells, meassure, options)
+}
+
+/// Statistics of the runs after the `warmup` runs
+fn run_stats(measurements: &Measured<()>, warmup: usize) -> anyhow::Result<meassure::Stats> {
+    measurements.stats(warmup).with_context(|| {
+        format!(
+            "all {} runs are warmup runs, measure more of them",
+            measurements.runs.len()
+        )
+    })
+}
+
+/// Compiles or loads `code`, only brainfuck source has a source
+fn compile_with_source(
+    code: &[u8],
+    extensions: compile::Extensions,
+) -> anyhow::Result<(Vec<OpCode>, Option<Source<'_>>)> {
+    Ok(if compile::is_bytecode(code) {
+        (compile::load(code)?, None)
+    } else {
+        let (ops, map) = compile::compile_with_source_map(code, extensions);
+        (ops, Some(Source::new(code, map)))
+    })
+}
+
+/// Prints the loops counted by the profiler of `options` to stderr, if there is one
+fn print_profile(code: &[u8], options: &Options) -> anyhow::Result<()> {
+    let Some(profiler) = &options.profile else {
+        return Ok(());
+    };
+    let (ops, source) = compile_with_source(code, options.extensions)?;
+    eprint!(
+        "{}",
+        profile::report(&profiler.loops(&ops), source.as_ref())
+    );
+    Ok(())
+}
+
+/// Prints the tape usage recorded by the coverage of `options` to stderr, if there is one
+fn print_coverage(options: &Options) {
+    if let Some(coverage) = &options.coverage {
+        eprint!("{}", coverage::report(coverage.stats()));
     }
+}
 
+fn write_executable(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    std::fs::write(path, content)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
     Ok(())
 }
 
-fn run_meassured<T: Runner>(code: &[u8], cells: usize, meassure: usize) -> Measured<()> {
-    let mut measured_ops = compile::compile_meassured(code);
+fn run_meassured<T: Runner>(
+    code: &[u8],
+    cells: usize,
+    meassure: usize,
+    options: &Options,
+) -> anyhow::Result<Measured<()>> {
+    let mut measured_ops = Measured::new();
+    let cached = options
+        .cache
+        .as_ref()
+        .and_then(|entry| measured_ops.measure("loading cache", || entry.load_ops()));
+    let mut measured_ops = match cached {
+        Some(ops) => {
+            measured_ops.set(ops);
+            measured_ops
+        }
+        None if compile::is_bytecode(code) => {
+            let ops = measured_ops.measure("loading", || compile::load(code))?;
+            measured_ops.set(ops);
+            measured_ops
+        }
+        None => {
+            let mut compiled =
+                measured_ops.append(compile::compile_meassured(code, options.extensions));
+            if let Some(entry) = &options.cache {
+                let ops = compiled.data();
+                entry.store_ops(&ops);
+                compiled.set(ops);
+            }
+            compiled
+        }
+    };
     let mut ops = measured_ops.data();
     let mut cells = vec![0u8; cells];
 
+    let input = compile::bundled_input(code, options.extensions);
     let mut printer = make_printer();
-    let mut scanner = make_scanner();
+    let mut scanner = make_scanner(input);
 
-    measured_ops.append(T::exec_bench(
+    Ok(measured_ops.append(T::exec_bench(
         &mut ops,
         &mut cells,
         &mut printer,
         &mut scanner,
         meassure,
-    ))
+        options,
+    )?))
 }
@@ -1,6 +1,16 @@
+use std::{fmt::Write, time::Duration};
+
 pub struct Measured<T> {
     pub data: Option<T>,
     pub measurements: Vec<(String, std::time::Duration)>,
+    /// Durations of the runs of the program, in the order they were run
+    pub runs: Vec<Duration>,
+}
+
+impl<T> Default for Measured<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T> Measured<T> {
@@ -8,6 +18,7 @@ impl<T> Measured<T> {
         Self {
             data: None,
             measurements: Vec::new(),
+            runs: Vec::new(),
         }
     }
 
@@ -27,15 +38,140 @@ impl<T> Measured<T> {
         ret
     }
 
+    /// [`Measured::measure`] for one run of the program
+    pub fn measure_run<Ret>(&mut self, func: impl FnOnce() -> Ret) -> Ret {
+        let now = std::time::Instant::now();
+        let ret = func();
+        self.runs.push(now.elapsed());
+        ret
+    }
+
+    /// Statistics of the runs after the first `warmup` ones, none if there are no such runs
+    pub fn stats(&self, warmup: usize) -> Option<Stats> {
+        Stats::new(self.runs.get(warmup..).unwrap_or_default())
+    }
+
+    /// Time of all measurements and runs
+    pub fn total(&self) -> Duration {
+        self.measurements.iter().map(|(_, d)| *d).sum::<Duration>() + self.runs.iter().sum()
+    }
+
     pub fn data(&mut self) -> T {
         self.data.take().unwrap()
     }
 
     pub fn append<D>(mut self, other: Measured<D>) -> Measured<D> {
         self.measurements.extend(other.measurements);
+        self.runs.extend(other.runs);
         Measured {
             data: other.data,
             measurements: self.measurements,
+            runs: self.runs,
+        }
+    }
+}
+
+/// Statistics of the durations of several runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub runs: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub mean: Duration,
+    /// Sample standard deviation
+    pub stddev: Duration,
+    /// 95th percentile by nearest rank
+    pub p95: Duration,
+}
+
+impl Stats {
+    /// Statistics of the `durations`, none if there are none
+    pub fn new(durations: &[Duration]) -> Option<Self> {
+        let mut sorted = durations.to_vec();
+        sorted.sort();
+        let runs = sorted.len();
+        let min = *sorted.first()?;
+        let median = if runs.is_multiple_of(2) {
+            (sorted[runs / 2 - 1] + sorted[runs / 2]) / 2
+        } else {
+            sorted[runs / 2]
+        };
+        let mean = sorted.iter().sum::<Duration>() / runs as u32;
+        let variance = match runs {
+            1 => 0.0,
+            _ => {
+                sorted
+                    .iter()
+                    .map(|d| (d.as_secs_f64() - mean.as_secs_f64()).powi(2))
+                    .sum::<f64>()
+                    / (runs - 1) as f64
+            }
+        };
+        Some(Self {
+            runs,
+            min,
+            median,
+            mean,
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+            p95: sorted[(runs * 95).div_ceil(100) - 1],
+        })
+    }
+}
+
+/// Table of the statistics of every named row, with the speedup of the medians relative to the
+/// median `baseline` if there is one
+pub fn table(rows: &[(String, Stats)], baseline: Option<Duration>) -> String {
+    let mut out = format!(
+        "{:<12} {:>5} {:>11} {:>11} {:>11} {:>11} {:>11}",
+        "", "runs", "min", "median", "mean", "stddev", "p95"
+    );
+    if baseline.is_some() {
+        write!(out, " {:>8}", "speedup").unwrap();
+    }
+    out.push('\n');
+    for (name, stats) in rows {
+        write!(
+            out,
+            "{name:<12} {:>5} {:>11.3?} {:>11.3?} {:>11.3?} {:>11.3?} {:>11.3?}",
+            stats.runs, stats.min, stats.median, stats.mean, stats.stddev, stats.p95
+        )
+        .unwrap();
+        if let Some(baseline) = baseline {
+            let speedup = baseline.as_secs_f64() / stats.median.as_secs_f64();
+            write!(out, " {:>7.2}x", speedup).unwrap();
         }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{table, Measured, Stats};
+
+    #[test]
+    fn run_stats() {
+        let mut m = Measured::<()>::new();
+        m.runs = [50, 10, 40, 20, 30]
+            .into_iter()
+            .map(Duration::from_millis)
+            .collect();
+
+        let ms = Duration::from_millis;
+        let stats = m.stats(1).unwrap();
+        assert_eq!(
+            (stats.runs, stats.min, stats.median, stats.mean, stats.p95),
+            (4, ms(10), ms(25), ms(25), ms(40))
+        );
+        // the sample standard deviation of 10, 20, 30 and 40 ms
+        assert_eq!(stats.stddev.as_micros(), 12_909);
+        assert_eq!(m.stats(5), None);
+        assert_eq!(Stats::new(&[ms(7)]).unwrap().stddev, Duration::ZERO);
+
+        let out = table(&[("jit".to_string(), stats)], Some(ms(100)));
+        assert!(out.lines().nth(1).unwrap().starts_with("jit"));
+        assert!(out.trim_end().ends_with("4.00x"));
     }
 }
@@ -0,0 +1,142 @@
+use std::{
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use crate::{compile::OpCode, source::Source};
+
+/// Counts how often every `[` and `]` is executed, clones share the counts.
+///
+/// The counts are indexed by op, so all profiled runs have to use the same ops.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler(Arc<Mutex<Vec<u64>>>);
+
+/// Executions of one loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Loop {
+    /// Index of the `[`
+    pub open: usize,
+    /// Index of the matching `]`
+    pub close: usize,
+    /// How often the `[` was reached
+    pub entries: u64,
+    /// How often the body was executed, every iteration ends at the `]`
+    pub iterations: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the counts of one run
+    pub(crate) fn add(&self, counts: &[u64]) {
+        let mut total = self.0.lock().unwrap();
+        if total.len() < counts.len() {
+            total.resize(counts.len(), 0);
+        }
+        for (total, count) in total.iter_mut().zip(counts) {
+            *total += count;
+        }
+    }
+
+    /// Every loop of `ops` which was reached, the loops with the most iterations first
+    pub fn loops(&self, ops: &[OpCode]) -> Vec<Loop> {
+        let counts = self.0.lock().unwrap();
+        let count = |op: usize| counts.get(op).copied().unwrap_or(0);
+
+        let mut open = Vec::new();
+        let mut loops = Vec::new();
+        for (index, op) in ops.iter().enumerate() {
+            match op {
+                OpCode::JumpIfZero { .. } => open.push(index),
+                OpCode::JumpIfNotZero { .. } => {
+                    let Some(start) = open.pop() else { continue };
+                    loops.push(Loop {
+                        open: start,
+                        close: index,
+                        entries: count(start),
+                        iterations: count(index),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        loops.retain(|l| l.entries > 0);
+        loops.sort_by(|a, b| {
+            b.iterations
+                .cmp(&a.iterations)
+                .then(b.entries.cmp(&a.entries))
+                .then(a.open.cmp(&b.open))
+        });
+        loops
+    }
+}
+
+/// Table of the `loops`, located in the `source` if there is one
+pub fn report(loops: &[Loop], source: Option<&Source>) -> String {
+    let mut out = format!("{:>12} {:>10}  loop\n", "iterations", "entries");
+    for l in loops {
+        let location = match source {
+            Some(source) => source.describe_ops(l.open, l.close),
+            None => format!("ops {}..={}", l.open, l.close),
+        };
+        writeln!(out, "{:>12} {:>10}  {location}", l.iterations, l.entries).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Loop, Profiler};
+    use crate::{
+        cljit::ClJit, compile, interpret::Interpreter, jit::Jit, threaded::Threaded,
+        tiered::Tiered, Options, Printer, Runner, Scanner,
+    };
+
+    fn profile<T: Runner>(code: &[u8]) -> Vec<Loop> {
+        let mut ops = compile::compile(code);
+        let profiler = Profiler::new();
+        let options = Options {
+            profile: Some(profiler.clone()),
+            ..Options::default()
+        };
+        let mut cells = vec![0u8; 100];
+        T::exec(
+            &mut ops,
+            &mut cells,
+            &mut Printer::new(|_| {}),
+            &mut Scanner::new(|| 0),
+            &options,
+        )
+        .unwrap();
+        profiler.loops(&ops)
+    }
+
+    #[test]
+    fn profile_loops() {
+        // the inner loop runs 8 times for each of the 255 iterations of the outer one, often
+        // enough to be compiled by the tiered runner
+        let code = b"-[>++++++++[->+<]<-]>>[-]";
+        let expected = vec![
+            Loop {
+                open: 4,
+                close: 7,
+                entries: 255,
+                iterations: 2040,
+            },
+            Loop {
+                open: 1,
+                close: 10,
+                entries: 1,
+                iterations: 255,
+            },
+        ];
+        assert_eq!(profile::<Interpreter>(code), expected);
+        assert_eq!(profile::<Threaded>(code), expected);
+        assert_eq!(profile::<Tiered>(code), expected);
+        assert_eq!(profile::<Jit>(code), expected);
+        assert_eq!(profile::<ClJit>(code), expected);
+    }
+}
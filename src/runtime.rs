@@ -0,0 +1,407 @@
+use crate::{
+    compile::OpCode,
+    coverage::{self, Coverage, Usage},
+    debug_function, grow_tape,
+    limit::Limiter,
+    profile::Profiler,
+    trace::Tracer,
+    Options, RunError, State,
+};
+
+/// Step budget of a run, shared with the generated code, which reads and calls its fields at the
+/// offsets below
+#[repr(C)]
+pub(crate) struct Budget {
+    /// steps left, decremented by the code at every `]`
+    pub(crate) counter: u64,
+    /// called once `counter` reaches zero, if it returns 1 the code stores the current cell index
+    /// in `cell` and returns
+    check: extern "C" fn(&mut Budget) -> u8,
+    /// cell index the run stopped at, the code starts at it if it was generated with
+    /// [`Instrumentation::start`]
+    pub(crate) cell: usize,
+    /// index of the op the run stopped at, stored by the code before calling `check`
+    pub(crate) op: usize,
+    /// called by `#` with the budget, the tape and the cell index
+    debug: extern "C" fn(&Budget, *const u8, usize),
+    /// counts of every executed `[` and `]`, indexed by op, incremented by profiled code
+    loop_counts: *mut u64,
+    /// called by traced code before every op with the budget, the tape, the cell index and the op
+    /// index, records the tape usage too
+    trace: extern "C" fn(&mut Budget, *const u8, usize, usize),
+    /// length of the tape
+    pub(crate) tape: usize,
+    /// called by code which grows the tape with the budget, the index of a cell at or right of
+    /// `tape` and the op index, returns the new tape or null if the run has to stop
+    grow: extern "C" fn(&mut Budget, usize, usize) -> *mut u8,
+    /// rest of the run, which the generated code only reaches through the functions above
+    pub(crate) run: RunState,
+}
+
+/// Offsets of the fields of [`Budget`] used by the generated code
+pub(crate) const COUNTER_OFFSET: i32 = 0;
+pub(crate) const CHECK_OFFSET: i32 = 8;
+pub(crate) const CELL_OFFSET: i32 = 16;
+pub(crate) const OP_OFFSET: i32 = 24;
+pub(crate) const DEBUG_OFFSET: i32 = 32;
+pub(crate) const LOOP_COUNTS_OFFSET: i32 = 40;
+pub(crate) const TRACE_OFFSET: i32 = 48;
+pub(crate) const TAPE_OFFSET: i32 = 56;
+pub(crate) const GROW_OFFSET: i32 = 64;
+
+/// Limits and instrumentation of a run which only the runners and the functions of the [`Budget`]
+/// use, so its layout is up to rustc
+pub(crate) struct RunState {
+    /// tape which `grow` resizes, null if the run does not grow its tape
+    cells: *mut Vec<u8>,
+    /// index of the first op of the generated code, which counts its ops from 0
+    first_op: usize,
+    /// owns the counts behind `loop_counts`, empty if the run is not profiled
+    counts: Vec<u64>,
+    /// gets the counts once the run is dropped
+    profiler: Option<Profiler>,
+    tracer: Option<Tracer>,
+    /// usage of this run and the coverage which gets it once the run is dropped
+    coverage: Option<(Coverage, Usage)>,
+    /// leaves out the next traced op
+    skip_trace: bool,
+    /// ops of the traced run, empty if it is neither traced nor its coverage recorded
+    ops: Vec<OpCode>,
+    /// checks the limits once `counter` reaches zero
+    limiter: Limiter,
+}
+
+/// Code which the JITs generate in addition to the ops, all of it uses the budget argument
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Instrumentation {
+    /// counts the steps at every `]`
+    pub(crate) steps: bool,
+    /// counts the executions of `[` and `]`
+    pub(crate) loops: bool,
+    /// traces every op and records the tape usage
+    pub(crate) trace: bool,
+    /// starts at the cell stored in the budget instead of the first one
+    pub(crate) start: bool,
+    /// grows the tape before a cell right of its end is used
+    pub(crate) grow: bool,
+}
+
+impl Instrumentation {
+    pub(crate) fn new(options: &Options) -> Self {
+        Self {
+            steps: !options.limits.is_unlimited(),
+            loops: options.profile.is_some(),
+            trace: options.trace.is_some() || options.coverage.is_some(),
+            start: options.start_cell != 0,
+            grow: options.grow_tape,
+        }
+    }
+
+    /// Returns if the run calls the budget while it executes the ops, the start cell is only read
+    /// once
+    pub(crate) fn uses_budget(&self) -> bool {
+        self.steps || self.loops || self.trace || self.grow
+    }
+}
+
+impl Budget {
+    /// Budget of one run of `ops` on the tape `cells`, with the limits, the profiler, the tracer
+    /// and the coverage of `options`, this starts the clock of the limits. Generated code has to
+    /// get the tape from [`Budget::tape_ptr`].
+    ///
+    /// Returns [`RunError::TapeLimit`] if the start cell is out of the tape and the tape can not
+    /// grow to it, the generated code would write out of it.
+    pub(crate) fn for_run(
+        options: &Options,
+        ops: &[OpCode],
+        cells: &mut Vec<u8>,
+    ) -> Result<Self, RunError> {
+        let mut limiter = Limiter::new(&options.limits);
+        if options.grow_tape {
+            grow_tape(cells, options.start_cell, limiter.max_cells());
+        }
+        if options.start_cell >= cells.len() {
+            return Err(RunError::TapeLimit(State {
+                cell: options.start_cell,
+                op: 0,
+                steps: 0,
+            }));
+        }
+        let mut budget = Self {
+            counter: limiter.refill(),
+            check: budget_function,
+            cell: options.start_cell,
+            op: 0,
+            debug: debug_function,
+            loop_counts: std::ptr::null_mut(),
+            trace: trace_function,
+            tape: 0,
+            grow: grow_function,
+            run: RunState {
+                cells: std::ptr::null_mut(),
+                first_op: 0,
+                counts: Vec::new(),
+                profiler: options.profile.clone(),
+                tracer: options.trace.clone(),
+                coverage: None,
+                skip_trace: false,
+                ops: Vec::new(),
+                limiter,
+            },
+        };
+        if options.grow_tape {
+            budget.run.cells = cells;
+        }
+        budget.tape = cells.len();
+        if options.profile.is_some() {
+            budget.run.counts = vec![0; ops.len()];
+            budget.loop_counts = budget.run.counts.as_mut_ptr();
+        }
+        budget.run.coverage = options.coverage.as_ref().map(|coverage| {
+            (
+                coverage.clone(),
+                Usage::new(cells.len(), options.start_cell),
+            )
+        });
+        if budget.run.tracer.is_some() || budget.run.coverage.is_some() {
+            budget.run.ops = ops.to_vec();
+        }
+        Ok(budget)
+    }
+
+    /// Grows `cells`, the tape of the run, so that `cell` is in it, returns true if the run has to
+    /// stop at the op with index `op` because the tape would exceed its limit
+    #[inline]
+    pub(crate) fn grow(&mut self, cells: &mut Vec<u8>, cell: usize, op: usize) -> bool {
+        if self.run.grow(cells, cell) {
+            self.tape = cells.len();
+            false
+        } else {
+            self.op = op;
+            true
+        }
+    }
+
+    /// Data pointer of `cells` to pass to the generated code. The tape which `grow` resizes is
+    /// stored again first, so both pointers are derived from the latest borrow of `cells`.
+    pub(crate) fn tape_ptr(&mut self, cells: &mut Vec<u8>) -> *mut u8 {
+        if self.run.cells.is_null() {
+            return cells.as_mut_ptr();
+        }
+        self.run.cells = cells;
+        // SAFETY: `self.run.cells` was just derived from `cells`
+        unsafe { (*self.run.cells).as_mut_ptr() }
+    }
+
+    /// Lets the generated code count and trace from op `first` on, for loops compiled on their own
+    pub(crate) fn ops_from(&mut self, first: usize) {
+        self.run.first_op = first;
+        if !self.run.counts.is_empty() {
+            self.loop_counts = self.run.counts[first..].as_mut_ptr();
+        }
+    }
+
+    /// Counts one step at the `]` with index `op`, returns true if the run has to stop
+    #[inline]
+    pub(crate) fn step(&mut self, op: usize) -> bool {
+        self.counter -= 1;
+        if self.counter == 0 {
+            self.op = op;
+            self.check()
+        } else {
+            false
+        }
+    }
+
+    fn check(&mut self) -> bool {
+        match self.run.limiter.check() {
+            Some(counter) => {
+                self.counter = counter;
+                false
+            }
+            None => {
+                // the step which stopped the run is not executed
+                self.counter = 1;
+                true
+            }
+        }
+    }
+
+    /// State of the run at the op with index `op`, `cell` is the current cell index
+    pub(crate) fn state(&self, cell: usize, op: usize) -> State {
+        self.run.limiter.state(self.counter, cell, op)
+    }
+
+    /// Returns the error if the run was stopped, `cell` is the current cell index
+    pub(crate) fn result(&self, cell: usize) -> Result<(), RunError> {
+        self.run.limiter.result(self.counter, cell, self.op)
+    }
+}
+
+impl RunState {
+    /// Traces the op with index `op` before it is executed and records its tape usage, if the
+    /// run is traced or its coverage recorded
+    #[inline]
+    pub(crate) fn trace(&mut self, op: usize, cells: &[u8], cell: usize) {
+        if self.ops.is_empty() || std::mem::take(&mut self.skip_trace) {
+            return;
+        }
+        if let Some(tracer) = &self.tracer {
+            tracer.trace(op, self.ops[op], cell, cells.get(cell).copied());
+        }
+        if let Some((_, usage)) = &mut self.coverage {
+            usage.record(self.ops[op], cell);
+        }
+    }
+
+    /// Leaves out the next traced op, which only this runner executes
+    pub(crate) fn skip_trace(&mut self) {
+        self.skip_trace = true;
+    }
+
+    /// Counts one execution of the `[` or `]` with index `op` if the run is profiled
+    #[inline]
+    pub(crate) fn count(&mut self, op: usize) {
+        if let Some(count) = self.counts.get_mut(op) {
+            *count += 1;
+        }
+    }
+
+    /// Takes back a count of [`RunState::count`]
+    pub(crate) fn uncount(&mut self, op: usize) {
+        if let Some(count) = self.counts.get_mut(op) {
+            *count -= 1;
+        }
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.limiter.is_stopped()
+    }
+
+    /// Grows `cells` so that `cell` is in it, stops the run and returns false if the tape would
+    /// exceed its limit
+    fn grow(&mut self, cells: &mut Vec<u8>, cell: usize) -> bool {
+        if !grow_tape(cells, cell, self.limiter.max_cells()) {
+            self.limiter.stop_tape();
+            return false;
+        }
+        if let Some((_, usage)) = &mut self.coverage {
+            usage.resize(cells.len());
+        }
+        true
+    }
+}
+
+impl Drop for RunState {
+    fn drop(&mut self) {
+        if let Some(profiler) = &self.profiler {
+            profiler.add(&self.counts);
+        }
+        if let Some(tracer) = &self.tracer {
+            if self.is_stopped() || std::thread::panicking() {
+                tracer.dump();
+            }
+        }
+        if let Some((coverage, usage)) = &self.coverage {
+            coverage.add(usage);
+            // the tape usage often explains the panic, e.g. a pointer left of the tape
+            if std::thread::panicking() {
+                eprint!("{}", coverage::report(coverage.stats()));
+            }
+        }
+    }
+}
+
+extern "C" fn budget_function(budget: &mut Budget) -> u8 {
+    budget.check() as u8
+}
+
+/// Returns the grown tape, or null if the run has to stop at the op with index `op`
+extern "C" fn grow_function(budget: &mut Budget, cell: usize, op: usize) -> *mut u8 {
+    // SAFETY: `cells` is the tape of the run, stored by `tape_ptr` right before the generated
+    // code was called, the runner does not use it during the call
+    let cells = unsafe { &mut *budget.run.cells };
+    if budget.grow(cells, cell, op) {
+        std::ptr::null_mut()
+    } else {
+        cells.as_mut_ptr()
+    }
+}
+
+extern "C" fn trace_function(budget: &mut Budget, cells: *const u8, cell: usize, op: usize) {
+    // SAFETY: `tape` is the length of the tape of the run
+    let cells = unsafe { std::slice::from_raw_parts(cells, budget.tape) };
+    budget.run.trace(budget.run.first_op + op, cells, cell);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cljit::ClJit, compile, interpret::Interpreter, jit::Jit, limit::Limits, threaded::Threaded,
+        tiered::Tiered, Options, Printer, RunError, Runner, Scanner, State,
+    };
+
+    #[test]
+    fn grow_tape_runners() {
+        fn run<T: Runner>(code: &[u8], limits: Limits) -> (Result<(), RunError>, Vec<u8>) {
+            let mut ops = compile::compile(code);
+            let options = Options {
+                grow_tape: true,
+                limits,
+                ..Options::default()
+            };
+            let mut cells = vec![0u8; 4];
+            let result = T::exec(
+                &mut ops,
+                &mut cells,
+                &mut Printer::new(|_| {}),
+                &mut Scanner::new(|| 0),
+                &options,
+            );
+            (result, cells)
+        }
+        fn tape<T: Runner>() -> Vec<u8> {
+            // moves a counter from cell 0 to cell 255, one cell right per iteration
+            let (result, cells) = run::<T>(b"-[[>+<-]>-]+", Limits::default());
+            result.unwrap();
+            cells
+        }
+        fn limited<T: Runner>() -> Result<(), RunError> {
+            // runs long enough for the tiered runner to compile the loop, the steps are only
+            // counted by all runners with a step limit
+            let limits = Limits {
+                steps: Some(1 << 20),
+                cells: Some(4096),
+                ..Limits::default()
+            };
+            let (result, cells) = run::<T>(b"+[>+]", limits);
+            assert_eq!(cells.len(), 4096);
+            result
+        }
+
+        let mut expected = vec![0u8; 256];
+        expected[255] = 1;
+        for cells in [
+            tape::<Interpreter>(),
+            tape::<Threaded>(),
+            tape::<Tiered>(),
+            tape::<Jit>(),
+            tape::<ClJit>(),
+        ] {
+            assert_eq!(cells[..256], expected);
+            assert!(cells[256..].iter().all(|cell| *cell == 0));
+        }
+
+        // the `>` which moves out of the limit stops the run
+        let stopped = Err(RunError::TapeLimit(State {
+            cell: 4096,
+            op: 2,
+            steps: 4095,
+        }));
+        assert_eq!(limited::<Interpreter>(), stopped);
+        assert_eq!(limited::<Threaded>(), stopped);
+        assert_eq!(limited::<Tiered>(), stopped);
+        assert_eq!(limited::<Jit>(), stopped);
+        assert_eq!(limited::<ClJit>(), stopped);
+    }
+}
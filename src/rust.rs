@@ -0,0 +1,145 @@
+use std::fmt::Write;
+
+use crate::compile::OpCode;
+
+/// Translates `ops` into a self-contained Rust program with a tape of `cells` cells.
+///
+/// The generated code has the same semantics as [`crate::interpret::Interpreter`], the end of the
+/// input reads as 0 and debug ops print to stderr like [`crate::dump_tape`].
+pub fn translate(ops: &[OpCode], cells: usize) -> String {
+    let input = ops.iter().any(|op| matches!(op, OpCode::Input));
+    let output = ops.iter().any(|op| matches!(op, OpCode::Output));
+
+    let mut out = String::new();
+    // moves after the last access of a cell are not optimized away
+    writeln!(out, "#![allow(unused_assignments)]\n").unwrap();
+    match (input, output) {
+        (true, true) => writeln!(out, "use std::io::{{Read, Write}};\n").unwrap(),
+        (true, false) => writeln!(out, "use std::io::Read;\n").unwrap(),
+        (false, true) => writeln!(out, "use std::io::Write;\n").unwrap(),
+        (false, false) => {}
+    }
+    if input {
+        writeln!(out, "fn scan(input: &mut impl Read) -> u8 {{").unwrap();
+        writeln!(out, "    let mut byte = [0];").unwrap();
+        writeln!(out, "    match input.read(&mut byte) {{").unwrap();
+        writeln!(out, "        Ok(1) => byte[0],").unwrap();
+        writeln!(out, "        _ => 0,").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}\n").unwrap();
+    }
+    if ops.contains(&OpCode::Debug) {
+        writeln!(out, "fn dump(cells: &[u8], cell: usize) {{").unwrap();
+        writeln!(out, "    let end = (cell + 9).min(cells.len());").unwrap();
+        writeln!(out, "    let start = cell.saturating_sub(8).min(end);").unwrap();
+        writeln!(
+            out,
+            "    eprint!(\"# pointer {{cell}}, cells {{start}}..{{end}}:\");"
+        )
+        .unwrap();
+        writeln!(out, "    for index in start..end {{").unwrap();
+        writeln!(out, "        if index == cell {{").unwrap();
+        writeln!(out, "            eprint!(\" [{{}}]\", cells[index]);").unwrap();
+        writeln!(out, "        }} else {{").unwrap();
+        writeln!(out, "            eprint!(\" {{}}\", cells[index]);").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "    eprintln!();").unwrap();
+        writeln!(out, "}}\n").unwrap();
+    }
+
+    writeln!(out, "fn main() {{").unwrap();
+    writeln!(out, "    let mut cells = vec![0u8; {cells}];").unwrap();
+    writeln!(out, "    let mut cell = 0usize;").unwrap();
+    if input {
+        writeln!(out, "    let mut input = std::io::stdin().lock();").unwrap();
+    }
+    if output {
+        writeln!(out, "    let mut output = std::io::stdout().lock();").unwrap();
+    }
+
+    let mut depth = 1;
+    for op in ops {
+        if let OpCode::JumpIfNotZero { .. } = op {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        match *op {
+            OpCode::Right { count } => writeln!(out, "{indent}cell += {count};"),
+            OpCode::Left { count } => writeln!(out, "{indent}cell -= {count};"),
+            OpCode::Inc { count, offset } => {
+                let index = index(offset);
+                writeln!(
+                    out,
+                    "{indent}cells[{index}] = cells[{index}].wrapping_add({count});"
+                )
+            }
+            OpCode::Dec { count, offset } => {
+                let index = index(offset);
+                writeln!(
+                    out,
+                    "{indent}cells[{index}] = cells[{index}].wrapping_sub({count});"
+                )
+            }
+            OpCode::Output => writeln!(out, "{indent}output.write_all(&[cells[cell]]).unwrap();"),
+            OpCode::Input => writeln!(out, "{indent}cells[cell] = scan(&mut input);"),
+            OpCode::JumpIfZero { .. } => {
+                depth += 1;
+                writeln!(out, "{indent}while cells[cell] != 0 {{")
+            }
+            OpCode::JumpIfNotZero { .. } => writeln!(out, "{indent}}}"),
+            OpCode::SetZero => writeln!(out, "{indent}cells[cell] = 0;"),
+            OpCode::Mul { factor, offset } => {
+                let index = index(offset);
+                writeln!(
+                    out,
+                    "{indent}cells[{index}] = cells[{index}].wrapping_add(cells[cell].wrapping_mul({factor}));\n\
+                     {indent}cells[cell] = 0;"
+                )
+            }
+            OpCode::Debug => writeln!(out, "{indent}dump(&cells, cell);"),
+        }
+        .unwrap();
+    }
+
+    if output {
+        writeln!(out, "    output.flush().unwrap();").unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// index expression of the current cell + `offset`
+fn index(offset: i32) -> String {
+    match offset {
+        0 => "cell".to_string(),
+        1.. => format!("cell + {offset}"),
+        _ => format!("cell - {}", offset.unsigned_abs()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compile,
+        interpret::Interpreter,
+        tests::{native_output, output, HELLO_INPUT},
+    };
+
+    #[test]
+    fn code_rust() {
+        let ops = compile::compile(b"+++[>++<-]>.");
+        let rust = super::translate(&ops, 30000);
+
+        assert!(rust.contains("use std::io::Write;\n\nfn main() {\n"));
+        assert!(rust.contains(
+            "    cells[cell + 1] = cells[cell + 1].wrapping_add(cells[cell].wrapping_mul(2));\n"
+        ));
+        assert!(rust.contains("    output.write_all(&[cells[cell]]).unwrap();\n"));
+
+        let rust = super::translate(&compile::compile(HELLO_INPUT), 30000);
+        if let Some(native) = native_output("rustc", &[], rust.as_bytes(), "rs") {
+            assert_eq!(native, output::<Interpreter>(HELLO_INPUT));
+        }
+    }
+}
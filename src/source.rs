@@ -0,0 +1,162 @@
+use std::ops::Range;
+
+/// Byte range in the source of every op, ops merged by the optimizer cover the ranges of all
+/// merged ops
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    pub(crate) spans: Vec<Range<usize>>,
+}
+
+impl SourceMap {
+    /// Source range of the op at `index`
+    pub fn span(&self, index: usize) -> Range<usize> {
+        self.spans[index].clone()
+    }
+
+    /// Returns the first op which ends after `offset`, so ops are found by any byte they cover
+    /// and offsets in comments resolve to the next op
+    pub fn op_at(&self, offset: usize) -> Option<usize> {
+        let index = self.spans.partition_point(|span| span.end <= offset);
+        (index < self.spans.len()).then_some(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+}
+
+/// Converts between byte offsets and 1 based line and column numbers
+#[derive(Debug, Clone)]
+pub struct Lines {
+    /// offset of the first byte of every line
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl Lines {
+    pub fn new(code: &[u8]) -> Self {
+        let starts = std::iter::once(0)
+            .chain(
+                code.iter()
+                    .enumerate()
+                    .filter(|(_, byte)| **byte == b'\n')
+                    .map(|(index, _)| index + 1),
+            )
+            .collect();
+        Self {
+            starts,
+            len: code.len(),
+        }
+    }
+
+    /// Returns (line, column) of `offset`
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|start| *start <= offset) - 1;
+        (line + 1, offset - self.starts[line] + 1)
+    }
+
+    /// Returns the offset of `line` and `column`, if the line exists
+    pub fn offset(&self, line: usize, column: usize) -> Option<usize> {
+        let start = *self.starts.get(line.checked_sub(1)?)?;
+        Some((start + column.saturating_sub(1)).min(self.len))
+    }
+
+    /// Byte range of `line` without the line break
+    pub fn line(&self, line: usize) -> Option<Range<usize>> {
+        let start = *self.starts.get(line.checked_sub(1)?)?;
+        let end = self
+            .starts
+            .get(line)
+            .map_or(self.len, |next| next - 1)
+            .max(start);
+        Some(start..end)
+    }
+}
+
+/// Source of a program together with the map of its ops
+#[derive(Debug, Clone)]
+pub struct Source<'a> {
+    code: &'a [u8],
+    map: SourceMap,
+    lines: Lines,
+}
+
+impl<'a> Source<'a> {
+    pub fn new(code: &'a [u8], map: SourceMap) -> Self {
+        Self {
+            code,
+            lines: Lines::new(code),
+            map,
+        }
+    }
+
+    pub fn map(&self) -> &SourceMap {
+        &self.map
+    }
+
+    /// (line, column) of the first byte of the op at `index`
+    pub fn position(&self, index: usize) -> (usize, usize) {
+        self.lines.position(self.map.span(index).start)
+    }
+
+    /// Range of the ops which end in the lines from `first` to `last`, both included
+    pub fn ops_in_lines(&self, first: usize, last: usize) -> Range<usize> {
+        let op_at = |line| {
+            self.lines
+                .offset(line, 1)
+                .and_then(|offset| self.map.op_at(offset))
+                .unwrap_or(self.map.len())
+        };
+        op_at(first)..op_at(last + 1).max(op_at(first))
+    }
+
+    /// `line:column` and the source text of the op at `index`, long texts are shortened
+    pub fn describe(&self, index: usize) -> String {
+        self.describe_ops(index, index)
+    }
+
+    /// [`Source::describe`] for the ops from `first` to `last`, both included
+    pub fn describe_ops(&self, first: usize, last: usize) -> String {
+        const MAX: usize = 24;
+        let span = self.map.span(first).start..self.map.span(last).end;
+        let text = String::from_utf8_lossy(&self.code[span])
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let text = if text.chars().count() > MAX {
+            format!("{}...", text.chars().take(MAX - 3).collect::<String>())
+        } else {
+            text
+        };
+        let (line, column) = self.position(first);
+        format!("{line}:{column} `{text}`")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lines, Source};
+    use crate::compile;
+
+    #[test]
+    fn source_positions() {
+        let code = b"+++ add\n[>+<-]\n.";
+        let (ops, map) = compile::compile_with_source_map(code, Default::default());
+        let lines = Lines::new(code);
+
+        assert_eq!(map.len(), ops.len());
+        assert_eq!(map.span(0), 0..3);
+        assert_eq!(map.span(1), 8..14);
+        assert_eq!(lines.position(map.span(1).start), (2, 1));
+        assert_eq!(map.op_at(lines.offset(1, 5).unwrap()), Some(1));
+        assert_eq!(lines.line(2), Some(8..14));
+        let source = Source::new(code, map);
+        assert_eq!(source.describe(1), "2:1 `[>+<-]`");
+        assert_eq!(source.describe_ops(0, 1), "1:1 `+++ add [>+<-]`");
+        assert_eq!(source.ops_in_lines(2, 2), 1..source.map().len() - 1);
+    }
+}
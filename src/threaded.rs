@@ -0,0 +1,249 @@
+use std::ops::ControlFlow;
+
+use crate::{
+    compile::OpCode,
+    dump_tape, printer_function,
+    runtime::{Budget, Instrumentation},
+    scanner_function, Measured, Options, Printer, RunError, Runner, Scanner,
+};
+
+/// Interpreter which compiles the ops into a tree of closures once, loops own their body, so no
+/// instruction pointer and no jump targets are needed at run time
+pub struct Threaded;
+
+struct State<'a> {
+    cells: &'a mut Vec<u8>,
+    cell: usize,
+    printer: &'a mut Printer,
+    scanner: &'a mut Scanner,
+    budget: Budget,
+}
+
+/// Breaks once the budget is used up or the tape can not grow
+type Handler = Box<dyn Fn(&mut State<'_>) -> ControlFlow<()>>;
+
+impl Threaded {
+    /// Every op but `]` is wrapped in a handler which traces it, if the run is traced, the
+    /// handlers grow the tape if it grows. Fails on unmatched brackets before any op runs.
+    fn compile(ops: &[OpCode], options: &Options) -> Result<Handler, RunError> {
+        let unmatched = |bracket, op| {
+            let state = crate::State {
+                cell: options.start_cell,
+                op,
+                steps: 0,
+            };
+            RunError::Compile(state, format!("unmatched `{bracket}`"))
+        };
+        let mut ops = ops.iter().enumerate();
+        match compile_block(&mut ops, Instrumentation::new(options)) {
+            Ok((block, None)) => Ok(Box::new(move |state| run_block(&block, state))),
+            Ok((_, Some(close))) => Err(unmatched(']', close)),
+            Err(open) => Err(unmatched('[', open)),
+        }
+    }
+
+    fn run(
+        program: &Handler,
+        cells: &mut Vec<u8>,
+        printer: &mut Printer,
+        scanner: &mut Scanner,
+        ops: &[OpCode],
+        options: &Options,
+    ) -> Result<(), RunError> {
+        let budget = Budget::for_run(options, ops, cells)?;
+        let mut state = State {
+            cells,
+            cell: options.start_cell,
+            printer,
+            scanner,
+            budget,
+        };
+        _ = program(&mut state);
+        state.budget.result(state.cell)
+    }
+}
+
+impl Runner for Threaded {
+    fn exec(
+        ops: &mut [OpCode],
+        cells: &mut Vec<u8>,
+        printer: &mut Printer,
+        scanner: &mut Scanner,
+        options: &Options,
+    ) -> Result<(), RunError> {
+        Threaded::run(
+            &Threaded::compile(ops, options)?,
+            cells,
+            printer,
+            scanner,
+            ops,
+            options,
+        )
+    }
+
+    fn exec_bench(
+        ops: &mut [OpCode],
+        cells: &mut Vec<u8>,
+        printer: &mut Printer,
+        scanner: &mut Scanner,
+        count: usize,
+        options: &Options,
+    ) -> Result<Measured<()>, RunError> {
+        let mut m = Measured::new();
+        let program = m.measure("compile closures", || Threaded::compile(ops, options))?;
+        for _ in 0..count {
+            m.measure_run(|| Threaded::run(&program, cells, printer, scanner, ops, options))?;
+        }
+        Ok(m)
+    }
+}
+
+fn run_block(block: &[Handler], state: &mut State<'_>) -> ControlFlow<()> {
+    for handler in block {
+        handler(state)?;
+    }
+    ControlFlow::Continue(())
+}
+
+/// The cell `offset` cells right of the current one, the tape grows to it if `grow` is set. Breaks
+/// at the op with index `op` if the tape can not grow.
+fn offset_cell<'a>(
+    s: &'a mut State<'_>,
+    offset: i32,
+    grow: bool,
+    op: usize,
+) -> ControlFlow<(), &'a mut u8> {
+    let cell = (s.cell as isize + offset as isize) as usize;
+    if grow && s.budget.grow(s.cells, cell, op) {
+        return ControlFlow::Break(());
+    }
+    ControlFlow::Continue(&mut s.cells[cell])
+}
+
+/// Compiles ops until the end of the current loop, returns the block and the index of the `]`,
+/// or the index of a `[` without a `]`
+fn compile_block<'a>(
+    ops: &mut impl Iterator<Item = (usize, &'a OpCode)>,
+    instrumentation: Instrumentation,
+) -> Result<(Vec<Handler>, Option<usize>), usize> {
+    let mut block: Vec<Handler> = Vec::new();
+    let grow = instrumentation.grow;
+
+    while let Some((index, op)) = ops.next() {
+        let handler: Handler = match *op {
+            OpCode::Right { count } => Box::new(move |s| {
+                s.cell += count as usize;
+                if grow && s.budget.grow(s.cells, s.cell, index) {
+                    return ControlFlow::Break(());
+                }
+                ControlFlow::Continue(())
+            }),
+            OpCode::Left { count } => Box::new(move |s| {
+                s.cell -= count as usize;
+                ControlFlow::Continue(())
+            }),
+            OpCode::Inc { count, offset } => Box::new(move |s| {
+                let cell = offset_cell(s, offset, grow, index)?;
+                *cell = cell.wrapping_add(count);
+                ControlFlow::Continue(())
+            }),
+            OpCode::Dec { count, offset } => Box::new(move |s| {
+                let cell = offset_cell(s, offset, grow, index)?;
+                *cell = cell.wrapping_sub(count);
+                ControlFlow::Continue(())
+            }),
+            OpCode::Output => Box::new(|s| {
+                printer_function(s.printer, s.cells[s.cell]);
+                ControlFlow::Continue(())
+            }),
+            OpCode::Input => Box::new(|s| {
+                s.cells[s.cell] = scanner_function(s.scanner);
+                ControlFlow::Continue(())
+            }),
+            OpCode::JumpIfZero { .. } => {
+                let (body, close) = compile_block(ops, instrumentation)?;
+                let Some(close) = close else {
+                    return Err(index);
+                };
+                Box::new(move |s| {
+                    s.budget.run.count(index);
+                    if s.cells[s.cell] == 0 {
+                        return ControlFlow::Continue(());
+                    }
+                    loop {
+                        run_block(&body, s)?;
+                        // the `]` of the loop
+                        s.budget.run.trace(close, s.cells, s.cell);
+                        if s.budget.step(close) {
+                            return ControlFlow::Break(());
+                        }
+                        s.budget.run.count(close);
+                        if s.cells[s.cell] == 0 {
+                            return ControlFlow::Continue(());
+                        }
+                    }
+                })
+            }
+            OpCode::JumpIfNotZero { .. } => return Ok((block, Some(index))),
+            OpCode::SetZero => Box::new(|s| {
+                s.cells[s.cell] = 0;
+                ControlFlow::Continue(())
+            }),
+            OpCode::Mul { factor, offset } => Box::new(move |s| {
+                let value = s.cells[s.cell].wrapping_mul(factor);
+                let cell = offset_cell(s, offset, grow, index)?;
+                *cell = cell.wrapping_add(value);
+                s.cells[s.cell] = 0;
+                ControlFlow::Continue(())
+            }),
+            OpCode::Debug => Box::new(|s| {
+                dump_tape(s.cells, s.cell);
+                ControlFlow::Continue(())
+            }),
+        };
+        block.push(if instrumentation.trace {
+            Box::new(move |s| {
+                s.budget.run.trace(index, s.cells, s.cell);
+                handler(s)
+            })
+        } else {
+            handler
+        });
+    }
+
+    Ok((block, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compile, interpret::Interpreter, tests::output, Options, Printer, RunError, Runner, Scanner,
+    };
+
+    use super::Threaded;
+
+    #[test]
+    fn code_threaded() {
+        let code = b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+        assert_eq!(output::<Threaded>(code), b"Hello World!\n");
+        assert_eq!(output::<Threaded>(code), output::<Interpreter>(code));
+
+        // unmatched brackets stop the run before its first op
+        let unmatched = |code: &[u8]| {
+            let result = Threaded::exec(
+                &mut compile::compile(code),
+                &mut vec![0u8; 8],
+                &mut Printer::new(|_| panic!("the program ran")),
+                &mut Scanner::new(|| 0),
+                &Options::default(),
+            );
+            match result {
+                Err(RunError::Compile(state, message)) => (state.op, message),
+                result => panic!("unexpected result {result:?}"),
+            }
+        };
+        assert_eq!(unmatched(b"+.]+."), (2, "unmatched `]`".to_string()));
+        assert_eq!(unmatched(b"+[[-]."), (1, "unmatched `[`".to_string()));
+    }
+}
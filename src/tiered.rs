@@ -0,0 +1,230 @@
+use crate::{
+    cljit::{self, LoopFunc},
+    compile::OpCode,
+    dump_tape,
+    interpret::back_patch,
+    printer_function,
+    runtime::{Budget, Instrumentation},
+    scanner_function, Measured, Options, Printer, RunError, Runner, Scanner,
+};
+
+/// Number of back-edges after which a loop is compiled
+const HOT_LOOP: u32 = 1000;
+
+/// Starts in the interpreter and compiles loops with cranelift once they are hot, the compiled
+/// loop gets the current cell index and the tape and returns the index after the loop
+pub struct Tiered;
+
+impl Tiered {
+    fn run(
+        ops: &[OpCode],
+        cells: &mut Vec<u8>,
+        printer: &mut Printer,
+        scanner: &mut Scanner,
+        options: &Options,
+    ) -> Result<(), RunError> {
+        let mut budget = Budget::for_run(options, ops, cells)?;
+        let instrumentation = Instrumentation::new(options);
+        // owns the code of all compiled loops, created for the first hot loop
+        let mut jit: Option<cljit::Jit> = None;
+        // back-edges taken per `JumpIfNotZero`
+        let mut back_edges = vec![0u32; ops.len()];
+        // compiled loops per `JumpIfZero`
+        let mut compiled: Vec<Option<LoopFunc>> = vec![None; ops.len()];
+
+        let mut ip = 0usize;
+        let mut cell = options.start_cell;
+        let grow = options.grow_tape;
+
+        while ip < ops.len() {
+            // compiled loops trace their ops themselves
+            if compiled[ip].is_none() {
+                budget.run.trace(ip, cells, cell);
+            }
+            match ops[ip] {
+                OpCode::Right { count } => {
+                    cell += count as usize;
+                    if grow && budget.grow(cells, cell, ip) {
+                        break;
+                    }
+                    ip += 1;
+                }
+                OpCode::Left { count } => {
+                    cell -= count as usize;
+                    ip += 1;
+                }
+                OpCode::Inc { count, offset } => {
+                    let cell = (cell as i32 + offset) as usize;
+                    if grow && budget.grow(cells, cell, ip) {
+                        break;
+                    }
+                    cells[cell] = cells[cell].wrapping_add(count);
+                    ip += 1;
+                }
+                OpCode::Dec { count, offset } => {
+                    let cell = (cell as i32 + offset) as usize;
+                    if grow && budget.grow(cells, cell, ip) {
+                        break;
+                    }
+                    cells[cell] = cells[cell].wrapping_sub(count);
+                    ip += 1;
+                }
+                OpCode::Output => {
+                    printer_function(printer, cells[cell]);
+                    ip += 1;
+                }
+                OpCode::Input => {
+                    cells[cell] = scanner_function(scanner);
+                    ip += 1;
+                }
+                OpCode::JumpIfZero { target } => {
+                    if let Some(func) = compiled[ip] {
+                        // the compiled loop counts its ops from the `[`
+                        budget.ops_from(ip);
+                        let tape = budget.tape_ptr(cells);
+                        cell = func(
+                            tape,
+                            printer,
+                            printer_function,
+                            scanner,
+                            scanner_function,
+                            &mut budget,
+                            cell,
+                        );
+                        if budget.run.is_stopped() {
+                            // the compiled loop counts its ops from the `[`
+                            budget.op += ip;
+                        }
+                        budget.result(budget.cell)?;
+                        ip = target;
+                    } else {
+                        budget.run.count(ip);
+                        ip = if cells[cell] == 0 { target } else { ip + 1 };
+                    }
+                }
+                OpCode::JumpIfNotZero { target } => {
+                    if budget.step(ip) {
+                        break;
+                    }
+                    budget.run.count(ip);
+                    if cells[cell] == 0 {
+                        ip += 1;
+                        continue;
+                    }
+
+                    let open = target - 1;
+                    back_edges[ip] += 1;
+                    if back_edges[ip] == HOT_LOOP {
+                        let jit = match &mut jit {
+                            Some(jit) => Ok(jit),
+                            None => cljit::Jit::new(&options.cranelift).map(|new| jit.insert(new)),
+                        };
+                        match jit.and_then(|jit| {
+                            jit.compile_loop(&ops[open..=ip], open, instrumentation)
+                        }) {
+                            Ok(func) => compiled[open] = Some(func),
+                            Err(e) => {
+                                let state = budget.state(cell, open);
+                                return Err(RunError::Compile(state, format!("{e:#}")));
+                            }
+                        }
+                    }
+                    // continue the loop in the compiled code if there is some
+                    ip = if compiled[open].is_some() {
+                        // which counts and traces the `[` again
+                        budget.run.uncount(open);
+                        budget.run.skip_trace();
+                        open
+                    } else {
+                        target
+                    };
+                }
+                OpCode::SetZero => {
+                    cells[cell] = 0;
+                    ip += 1;
+                }
+                OpCode::Mul { factor, offset } => {
+                    let off_cell = (cell as i32 + offset) as usize;
+                    if grow && budget.grow(cells, off_cell, ip) {
+                        break;
+                    }
+
+                    cells[off_cell] =
+                        cells[off_cell].wrapping_add(cells[cell].wrapping_mul(factor));
+                    cells[cell] = 0;
+                    ip += 1;
+                }
+                OpCode::Debug => {
+                    dump_tape(cells, cell);
+                    ip += 1;
+                }
+            }
+        }
+        budget.result(cell)
+    }
+}
+
+impl Runner for Tiered {
+    fn exec(
+        ops: &mut [OpCode],
+        cells: &mut Vec<u8>,
+        printer: &mut Printer,
+        scanner: &mut Scanner,
+        options: &Options,
+    ) -> Result<(), RunError> {
+        back_patch(ops);
+        Tiered::run(ops, cells, printer, scanner, options)
+    }
+
+    fn exec_bench(
+        ops: &mut [OpCode],
+        cells: &mut Vec<u8>,
+        printer: &mut Printer,
+        scanner: &mut Scanner,
+        count: usize,
+        options: &Options,
+    ) -> Result<Measured<()>, RunError> {
+        let mut m = Measured::new();
+        m.measure("back patching", || back_patch(ops));
+        for _ in 0..count {
+            m.measure_run(|| Tiered::run(ops, cells, printer, scanner, options))?;
+        }
+        Ok(m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cljit, compile, interpret::Interpreter, tests::output, Options, Printer, RunError, Runner,
+        Scanner,
+    };
+
+    use super::Tiered;
+
+    #[test]
+    fn code_tiered() {
+        // the inner loop runs 255 * 255 times, so it is compiled while the outer one is not
+        let code = b"-[>-[>+<.-]>[>+<-]>.<<<-]>>>.";
+
+        assert_eq!(output::<Tiered>(code), output::<Interpreter>(code));
+
+        // a loop which can not be compiled stops the run at its `[`
+        let mut ops = compile::compile(code);
+        let options = Options {
+            cranelift: cljit::Settings {
+                flags: vec![("no_such_flag".to_string(), "true".to_string())],
+                ..cljit::Settings::default()
+            },
+            ..Options::default()
+        };
+        let result = Tiered::exec(
+            &mut ops,
+            &mut vec![0u8; 16],
+            &mut Printer::new(|_| {}),
+            &mut Scanner::new(|| 0),
+            &options,
+        );
+        assert!(matches!(result, Err(RunError::Compile(state, _)) if state.op == 4));
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::VecDeque,
+    io::Write,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use crate::compile::OpCode;
+
+/// Writes one line per executed op: its index, the op, the cell pointer and the value of the
+/// current cell before the op is executed. Clones share the output.
+///
+/// Jump targets are left out of the lines, so the traces of all runners can be compared.
+#[derive(Clone)]
+pub struct Tracer(Arc<Mutex<Trace>>);
+
+struct Trace {
+    out: Box<dyn Write + Send>,
+    /// only the ops in this range are traced
+    ops: Range<usize>,
+    /// the last steps and their maximum count, if only the end of stopped runs is written
+    ring: Option<(VecDeque<Step>, usize)>,
+}
+
+struct Step {
+    ip: usize,
+    op: OpCode,
+    cell: usize,
+    value: Option<u8>,
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", self.ip)?;
+        match self.op {
+            OpCode::JumpIfZero { .. } => write!(f, "JumpIfZero")?,
+            OpCode::JumpIfNotZero { .. } => write!(f, "JumpIfNotZero")?,
+            op => write!(f, "{op:?}")?,
+        }
+        match self.value {
+            Some(value) => write!(f, " pointer {} value {value}", self.cell),
+            None => write!(f, " pointer {} out of the tape", self.cell),
+        }
+    }
+}
+
+impl std::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tracer").finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    /// Traces every op to `out`
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Trace {
+            out: Box::new(out),
+            ops: 0..usize::MAX,
+            ring: None,
+        })))
+    }
+
+    /// Only traces the ops with an index in `ops`
+    pub fn filter(self, ops: Range<usize>) -> Self {
+        self.0.lock().unwrap().ops = ops;
+        self
+    }
+
+    /// Keeps only the last `len` steps and writes them once a run is stopped by its limits or
+    /// panics, the generated code of the JITs can not be traced when it crashes
+    pub fn ring(self, len: usize) -> Self {
+        self.0.lock().unwrap().ring = Some((VecDeque::with_capacity(len), len));
+        self
+    }
+
+    pub(crate) fn trace(&self, ip: usize, op: OpCode, cell: usize, value: Option<u8>) {
+        let mut trace = self.0.lock().unwrap();
+        if !trace.ops.contains(&ip) {
+            return;
+        }
+        let step = Step {
+            ip,
+            op,
+            cell,
+            value,
+        };
+        match &mut trace.ring {
+            Some((steps, len)) => {
+                if steps.len() == *len {
+                    steps.pop_front();
+                }
+                steps.push_back(step);
+            }
+            // a broken trace file must not stop the program
+            None => _ = writeln!(trace.out, "{step}"),
+        }
+    }
+
+    /// Writes the steps kept by [`Tracer::ring`]
+    pub(crate) fn dump(&self) {
+        let mut trace = self.0.lock().unwrap();
+        let Trace { out, ring, .. } = &mut *trace;
+        if let Some((steps, _)) = ring {
+            for step in steps.drain(..) {
+                _ = writeln!(out, "{step}");
+            }
+        }
+        _ = out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use super::Tracer;
+    use crate::{
+        cljit::ClJit, compile, interpret::Interpreter, jit::Jit, limit::Limits, threaded::Threaded,
+        tiered::Tiered, Options, Printer, Runner, Scanner,
+    };
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace<T: Runner>(code: &[u8], tracer: impl Fn(Tracer) -> Tracer, limits: Limits) -> String {
+        let buffer = Buffer::default();
+        let mut ops = compile::compile(code);
+        let options = Options {
+            trace: Some(tracer(Tracer::new(buffer.clone()))),
+            limits,
+            ..Options::default()
+        };
+        let mut cells = vec![0u8; 100];
+        _ = T::exec(
+            &mut ops,
+            &mut cells,
+            &mut Printer::new(|_| {}),
+            &mut Scanner::new(|| 0),
+            &options,
+        );
+        drop(options);
+        let out = buffer.0.lock().unwrap().clone();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn trace_runners() {
+        let code = b"++[>+++[->+<]<-]";
+        let full = trace::<Interpreter>(code, |t| t, Limits::default());
+        assert!(full.starts_with("0 Inc { count: 2, offset: 0 } pointer 0 value 0\n1 JumpIfZero"));
+        assert_eq!(full.lines().count(), 32);
+        assert_eq!(trace::<Threaded>(code, |t| t, Limits::default()), full);
+        assert_eq!(trace::<Tiered>(code, |t| t, Limits::default()), full);
+        assert_eq!(trace::<Jit>(code, |t| t, Limits::default()), full);
+        assert_eq!(trace::<ClJit>(code, |t| t, Limits::default()), full);
+
+        let inner = trace::<Jit>(code, |t| t.filter(4..8), Limits::default());
+        assert!(inner
+            .lines()
+            .all(|line| line.starts_with(['4', '5', '6', '7'])));
+
+        // nothing is written without a limit
+        assert_eq!(trace::<Jit>(code, |t| t.ring(3), Limits::default()), "");
+        let limits = Limits {
+            steps: Some(4),
+            ..Limits::default()
+        };
+        let last = trace::<ClJit>(code, |t| t.ring(3), limits);
+        assert_eq!(last.lines().count(), 3);
+        assert!(last.ends_with("7 JumpIfNotZero pointer 1 value 2\n"));
+    }
+}
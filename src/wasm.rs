@@ -0,0 +1,219 @@
+use std::fmt::Write;
+
+use wasm_encoder::{
+    BlockType, CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection,
+    ImportSection, Instruction, MemArg, MemorySection, MemoryType, Module, TypeSection, ValType,
+};
+
+use crate::compile::OpCode;
+
+const PAGE_SIZE: usize = 0x1_0000;
+/// Local holding the index of the current cell
+const POINTER: u32 = 0;
+const PRINT: u32 = 0;
+const SCAN: u32 = 1;
+const MEM: MemArg = MemArg {
+    offset: 0,
+    align: 0,
+    memory_index: 0,
+};
+
+/// Translates `ops` into a wasm module.
+///
+/// The module imports `env.print(i32)` and `env.scan() -> i32`, which are the equivalents of
+/// [`crate::PrinterFunc`] and [`crate::ScannerFunc`], exports its tape of `cells` cells as
+/// `memory` and the program as `run`. Debug ops are skipped.
+pub fn translate(ops: &[OpCode], cells: usize) -> Vec<u8> {
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::I32], []);
+    types.ty().function([], [ValType::I32]);
+    types.ty().function([], []);
+
+    let mut imports = ImportSection::new();
+    imports.import("env", "print", EntityType::Function(0));
+    imports.import("env", "scan", EntityType::Function(1));
+
+    let mut functions = FunctionSection::new();
+    functions.function(2);
+
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: pages(cells),
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+
+    let mut exports = ExportSection::new();
+    exports.export("memory", ExportKind::Memory, 0);
+    exports.export("run", ExportKind::Func, 2);
+
+    let mut run = Function::new([(1, ValType::I32)]);
+    for instruction in lower(ops) {
+        run.instruction(&instruction);
+    }
+    let mut code = CodeSection::new();
+    code.function(&run);
+
+    let mut module = Module::new();
+    module
+        .section(&types)
+        .section(&imports)
+        .section(&functions)
+        .section(&memories)
+        .section(&exports)
+        .section(&code);
+    module.finish()
+}
+
+/// Translates `ops` into the text format of the module generated by [`translate`]
+pub fn translate_wat(ops: &[OpCode], cells: usize) -> String {
+    let mut out = String::new();
+    writeln!(out, "(module").unwrap();
+    writeln!(out, "  (type (;0;) (func (param i32)))").unwrap();
+    writeln!(out, "  (type (;1;) (func (result i32)))").unwrap();
+    writeln!(out, "  (type (;2;) (func))").unwrap();
+    writeln!(out, "  (import \"env\" \"print\" (func $print (type 0)))").unwrap();
+    writeln!(out, "  (import \"env\" \"scan\" (func $scan (type 1)))").unwrap();
+    writeln!(out, "  (memory (export \"memory\") {})", pages(cells)).unwrap();
+    writeln!(out, "  (func (export \"run\") (type 2) (local $p i32)").unwrap();
+
+    let mut instructions = lower(ops);
+    // the end of the function body is implicit in the text format
+    instructions.pop();
+
+    let mut depth = 2;
+    for instruction in instructions {
+        if let Instruction::End = instruction {
+            depth -= 1;
+        }
+        let indent = "  ".repeat(depth);
+        let text = match instruction {
+            Instruction::LocalGet(POINTER) => "local.get $p".to_string(),
+            Instruction::LocalSet(POINTER) => "local.set $p".to_string(),
+            Instruction::I32Const(value) => format!("i32.const {value}"),
+            Instruction::I32Add => "i32.add".to_string(),
+            Instruction::I32Sub => "i32.sub".to_string(),
+            Instruction::I32Mul => "i32.mul".to_string(),
+            Instruction::I32Eqz => "i32.eqz".to_string(),
+            Instruction::I32Load8U(_) => "i32.load8_u".to_string(),
+            Instruction::I32Store8(_) => "i32.store8".to_string(),
+            Instruction::Call(PRINT) => "call $print".to_string(),
+            Instruction::Call(SCAN) => "call $scan".to_string(),
+            Instruction::Block(_) => "block".to_string(),
+            Instruction::Loop(_) => "loop".to_string(),
+            Instruction::BrIf(depth) => format!("br_if {depth}"),
+            Instruction::End => "end".to_string(),
+            instruction => unreachable!("{instruction:?} is not generated by lower"),
+        };
+        writeln!(out, "{indent}{text}").unwrap();
+        if let Instruction::Block(_) | Instruction::Loop(_) = instruction {
+            depth += 1;
+        }
+    }
+
+    writeln!(out, "  )").unwrap();
+    writeln!(out, ")").unwrap();
+    out
+}
+
+fn pages(cells: usize) -> u64 {
+    cells.div_ceil(PAGE_SIZE).max(1) as u64
+}
+
+/// Lowers `ops` into the body of the `run` function, the closing `end` included
+fn lower(ops: &[OpCode]) -> Vec<Instruction<'static>> {
+    use Instruction as I;
+    let mut code = Vec::new();
+
+    // pushes the address of the current cell + `offset`
+    let address = |code: &mut Vec<I>, offset: i32| {
+        code.push(I::LocalGet(POINTER));
+        if offset != 0 {
+            code.extend([I::I32Const(offset), I::I32Add]);
+        }
+    };
+    let current_cell = [I::LocalGet(POINTER), I::I32Load8U(MEM)];
+
+    for op in ops {
+        match *op {
+            OpCode::Right { count } => code.extend([
+                I::LocalGet(POINTER),
+                I::I32Const(count as i32),
+                I::I32Add,
+                I::LocalSet(POINTER),
+            ]),
+            OpCode::Left { count } => code.extend([
+                I::LocalGet(POINTER),
+                I::I32Const(count as i32),
+                I::I32Sub,
+                I::LocalSet(POINTER),
+            ]),
+            OpCode::Inc { count, offset } | OpCode::Dec { count, offset } => {
+                address(&mut code, offset);
+                address(&mut code, offset);
+                code.extend([I::I32Load8U(MEM), I::I32Const(count as i32)]);
+                code.push(match op {
+                    OpCode::Inc { .. } => I::I32Add,
+                    _ => I::I32Sub,
+                });
+                code.push(I::I32Store8(MEM));
+            }
+            OpCode::Output => {
+                code.extend(current_cell.clone());
+                code.push(I::Call(PRINT));
+            }
+            OpCode::Input => code.extend([I::LocalGet(POINTER), I::Call(SCAN), I::I32Store8(MEM)]),
+            OpCode::JumpIfZero { .. } => {
+                code.push(I::Block(BlockType::Empty));
+                code.extend(current_cell.clone());
+                code.extend([I::I32Eqz, I::BrIf(0), I::Loop(BlockType::Empty)]);
+            }
+            OpCode::JumpIfNotZero { .. } => {
+                code.extend(current_cell.clone());
+                code.extend([I::BrIf(0), I::End, I::End]);
+            }
+            OpCode::SetZero => {
+                code.extend([I::LocalGet(POINTER), I::I32Const(0), I::I32Store8(MEM)]);
+            }
+            OpCode::Mul { factor, offset } => {
+                address(&mut code, offset);
+                address(&mut code, offset);
+                code.push(I::I32Load8U(MEM));
+                code.extend(current_cell.clone());
+                code.extend([
+                    I::I32Const(factor as i32),
+                    I::I32Mul,
+                    I::I32Add,
+                    I::I32Store8(MEM),
+                ]);
+                code.extend([I::LocalGet(POINTER), I::I32Const(0), I::I32Store8(MEM)]);
+            }
+            // the module has no way to print to stderr
+            OpCode::Debug => {}
+        }
+    }
+
+    code.push(I::End);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compile, tests::HELLO_INPUT};
+
+    #[test]
+    fn code_wasm() {
+        let ops = compile::compile(b",[->+<]>.");
+        let wasm = super::translate(&ops, 30000);
+        let wat = super::translate_wat(&ops, 30000);
+
+        assert!(wasm.starts_with(b"\0asm"));
+        wasmparser::validate(&wasm).unwrap();
+        let ops = compile::compile(HELLO_INPUT);
+        wasmparser::validate(&super::translate(&ops, 30000)).unwrap();
+        assert!(wat.contains("    block\n"));
+        assert!(wat.trim_end().ends_with("  )\n)"));
+    }
+}